@@ -133,7 +133,7 @@ impl FromStr for Color {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s.is_empty() {
             Ok(Self(None))
-        } else if s.len() == 7
+        } else if [4, 7, 9].contains(&s.len()) // #RGB, #RRGGBB or #AARRGGBB
             && s.starts_with("#")
             && s.chars().skip(1).all(|c| c.is_ascii_hexdigit())
         {
@@ -4,74 +4,128 @@ use {
         Colors,
     },
     nix::{
-        sys::signal::{kill, Signal::SIGTERM},
+        errno::Errno,
+        poll::{poll, PollFd, PollFlags},
+        sys::{
+            signal::{kill, SigSet, Signal},
+            signalfd::{SfdFlags, SignalFd},
+        },
         unistd::Pid,
     },
-    posixmq::{unlink, OpenOptions},
-    simple_signal::Signal,
+    posixmq::{unlink, OpenOptions, PosixMq},
     std::{
         collections::HashMap,
-        io::{self, BufRead, BufReader, BufWriter, Write},
+        io::{self, BufWriter, ErrorKind, Read, Write},
+        os::unix::{io::AsRawFd, process::CommandExt},
         process::{Child, ChildStdout, Command, Stdio},
-        sync::{mpsc, Arc, RwLock},
-        thread,
     },
 };
 
-type Fields = Arc<HashMap<&'static str, Arc<RwLock<String>>>>;
-type Sender = mpsc::Sender<Result<(), Arc<io::Error>>>;
+type Fields = HashMap<&'static str, String>;
 type Res<T> = io::Result<T>;
-type Value = Arc<RwLock<String>>;
-type CmdOut = BufReader<ChildStdout>;
+
+/// A command of a field with the incomplete line of its output.  `stdout` is `None` after
+/// the end of output.
+struct Cmd {
+    field: &'static str,
+    child: Child,
+    stdout: Option<ChildStdout>,
+    buf: Vec<u8>,
+}
 
 pub fn run(colors: Colors) -> Res<()> {
     let stdout = io::stdout();
     let mut stdout = BufWriter::new(stdout.lock());
-    let (tx, rx) = mpsc::channel();
-    let fields = create_fields();
-
-    let children = start_commands(&fields, &tx)?;
-    simple_signal::set_handler(&[Signal::Term, Signal::Int], move |_| {
-        for child in &children {
-            if let Err(e) = kill(Pid::from_raw(child.id() as i32), SIGTERM) {
-                eprintln!("{}", e);
+    let mut fields = create_fields();
+
+    let mask = signal_mask();
+    mask.thread_block().map_err(nix_error)?;
+    let mut signals = SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)
+        .map_err(nix_error)?;
+
+    let mut commands = start_commands()?;
+    let mq = start_listener()?;
+    let mut buf = [0; MAX_MSG_LEN];
+
+    loop {
+        let mut fds = vec![
+            PollFd::new(signals.as_raw_fd(), PollFlags::POLLIN),
+            PollFd::new(mq.as_raw_fd(), PollFlags::POLLIN),
+        ];
+        let running: Vec<usize> = (0..commands.len())
+            .filter(|&i| commands[i].stdout.is_some())
+            .collect();
+        for &i in &running {
+            let fd = commands[i].stdout.as_ref().unwrap().as_raw_fd();
+            fds.push(PollFd::new(fd, PollFlags::POLLIN));
+        }
+
+        match poll(&mut fds, -1) {
+            Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => (),
+            Err(e) => return Err(nix_error(e)),
+        }
+        let ready = |fd: &PollFd| fd.revents().is_some_and(|events| !events.is_empty());
+
+        if ready(&fds[0]) && signals.read_signal().map_err(nix_error)?.is_some() {
+            for command in &commands {
+                if let Err(e) = kill(Pid::from_raw(command.child.id() as i32), Signal::SIGTERM) {
+                    eprintln!("{}", e);
+                }
             }
+            let _ = unlink(MQUEUE);
+            return Ok(());
         }
-        std::process::exit(0);
-    });
 
-    start_listener(Arc::clone(&fields), tx)?;
+        let mut changed = ready(&fds[1]) && receive(&mq, &mut buf, &mut fields)?;
+        for (&i, fd) in running.iter().zip(&fds[2..]) {
+            if ready(fd) {
+                changed |= commands[i].read(&mut fields)?;
+            }
+        }
 
-    for msg in rx {
-        msg.map_err(|e| Arc::try_unwrap(e).unwrap())?;
-        print_fields(&colors, &mut stdout, &fields)?;
+        if changed {
+            print_fields(&colors, &mut stdout, &fields)?;
+        }
     }
-    Ok(())
+}
+
+fn nix_error(e: nix::Error) -> io::Error {
+    io::Error::other(e)
+}
+
+fn signal_mask() -> SigSet {
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGTERM);
+    mask.add(Signal::SIGINT);
+    mask
 }
 
 fn create_fields() -> Fields {
-    let mut fields: HashMap<&'static str, _> = HashMap::with_capacity(FIELDS.len());
-    for (field, _, _, default) in FIELDS {
-        let default = Arc::new(RwLock::new(default.to_string()));
-        fields.insert(field, Arc::clone(&default));
-    }
-    Arc::new(fields)
+    FIELDS
+        .iter()
+        .map(|&(field, _, _, default)| (field, default.to_string()))
+        .collect()
 }
 
-fn start_commands(fields: &Fields, tx: &Sender) -> Res<Vec<Child>> {
-    let mut children = Vec::with_capacity(FIELDS.iter().filter(|f| f.2.is_some()).count());
+fn start_commands() -> Res<Vec<Cmd>> {
+    let mut commands = Vec::with_capacity(FIELDS.iter().filter(|f| f.2.is_some()).count());
     for (field, _, command, _) in FIELDS {
         if let Some(command) = command {
-            let value = Arc::clone(fields.get(field).unwrap());
-            children.push(start_command(value, command, tx.clone())?);
+            let (child, stdout) = command_stdout(command)?;
+            commands.push(Cmd {
+                field,
+                child,
+                stdout: Some(stdout),
+                buf: Vec::new(),
+            });
         }
     }
-    Ok(children)
+    Ok(commands)
 }
 
 fn print_fields(c: &Colors, stdout: &mut dyn Write, fields: &Fields) -> Res<()> {
     let mut iter = FIELDS.iter().map(|f| (f.0, f.1));
-    let get_field = |f| fields.get(f).unwrap().read().unwrap();
+    let get_field = |f| fields.get(f).unwrap();
 
     if let Some((field, hd)) = iter.next() {
         let (head, body) = (c.head.draw(hd), c.body.draw(get_field(field)));
@@ -87,44 +141,63 @@ fn print_fields(c: &Colors, stdout: &mut dyn Write, fields: &Fields) -> Res<()>
     stdout.flush()
 }
 
-fn command_stdout(command: &[&str]) -> Res<(Child, CmdOut)> {
-    let mut child = Command::new(command[0])
-        .args(&command[1..])
-        .stdout(Stdio::piped())
-        .spawn()?;
+fn command_stdout(command: &[&str]) -> Res<(Child, ChildStdout)> {
+    let mut child = Command::new(command[0]);
+    child.args(&command[1..]).stdout(Stdio::piped());
+    // Signals blocked for the signalfd are inherited, unblock them in the command
+    unsafe {
+        child.pre_exec(|| {
+            signal_mask()
+                .thread_unblock()
+                .map_err(|_| io::Error::last_os_error())
+        });
+    }
+    let mut child = child.spawn()?;
 
     let stdout = child
         .stdout
         .take()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No stdout of process"))?;
+        .ok_or_else(|| io::Error::other("No stdout of process"))?;
 
-    Ok((child, BufReader::new(stdout)))
+    Ok((child, stdout))
 }
 
-fn start_command(value: Value, command: &[&str], tx: Sender) -> Res<Child> {
-    let mut new_buf = String::new();
-    let (child, mut stdout) = command_stdout(command)?;
-
-    thread::spawn(move || loop {
-        match stdout.read_line(&mut new_buf) {
-            Ok(0) => break,
-            Ok(_) => {
-                new_buf.pop();
-                let mut buf = value.write().unwrap();
-                if new_buf != *buf {
-                    std::mem::swap(&mut *buf, &mut new_buf);
-                    tx.send(Ok(())).unwrap();
-                }
-                new_buf.clear();
-            }
-            Err(e) => {
-                tx.send(Err(Arc::new(e))).unwrap();
-                break;
-            }
+impl Cmd {
+    /// Reads available output; the last complete line is the value of the field.  Returns
+    /// whether the value changed.
+    fn read(&mut self, fields: &mut Fields) -> Res<bool> {
+        let mut chunk = [0; 1024];
+        let len = match self.stdout.as_mut().unwrap().read(&mut chunk) {
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::Interrupted => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        if len == 0 {
+            self.stdout = None;
+            return Ok(false);
         }
-    });
+        self.buf.extend_from_slice(&chunk[..len]);
+
+        let end = match self.buf.iter().rposition(|&byte| byte == b'\n') {
+            Some(end) => end,
+            None => return Ok(false),
+        };
+        let lines: Vec<u8> = self.buf.drain(..=end).collect();
+        let line = lines[..end].rsplit(|&byte| byte == b'\n').next().unwrap();
+        Ok(update(fields, self.field, &String::from_utf8_lossy(line)))
+    }
+}
 
-    Ok(child)
+fn update(fields: &mut Fields, field: &'static str, data: &str) -> bool {
+    let value = fields.get_mut(field).unwrap();
+    let is_new = *value != data;
+
+    if is_new {
+        value.clear();
+        value.push_str(data);
+    }
+
+    is_new
 }
 
 fn find_field<'a>(data: &'a std::borrow::Cow<str>) -> Option<(&'static str, &'a str)> {
@@ -135,37 +208,31 @@ fn find_field<'a>(data: &'a std::borrow::Cow<str>) -> Option<(&'static str, &'a
         .map(|field| (field, &data[field.len()..]))
 }
 
-fn start_listener(fields: Fields, tx: Sender) -> Res<()> {
+fn start_listener() -> Res<PosixMq> {
     if let Err(_) = unlink(MQUEUE) {}
 
-    let mq = OpenOptions::readonly()
+    OpenOptions::readonly()
+        .nonblocking()
         .max_msg_len(MAX_MSG_LEN)
         .capacity(CAPACITY)
         .create_new()
-        .open(MQUEUE)?;
-    let mut buf = [0; MAX_MSG_LEN];
+        .open(MQUEUE)
+}
 
-    thread::spawn(move || loop {
-        match mq.receive(&mut buf) {
+/// Receives all queued messages.  Returns whether a value changed.
+fn receive(mq: &PosixMq, buf: &mut [u8], fields: &mut Fields) -> Res<bool> {
+    let mut changed = false;
+    loop {
+        match mq.receive(buf) {
             Ok((_, len)) => {
                 let data = String::from_utf8_lossy(&buf[..len]);
 
                 if let Some((field, data)) = find_field(&data) {
-                    let mut value = fields.get(field).unwrap().write().unwrap();
-
-                    if *value != data {
-                        value.clear();
-                        value.push_str(data);
-                        tx.send(Ok(())).unwrap();
-                    }
+                    changed |= update(fields, field, data);
                 }
             }
-            Err(e) => {
-                tx.send(Err(Arc::new(e))).unwrap();
-                break;
-            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(changed),
+            Err(e) => return Err(e),
         }
-    });
-
-    Ok(())
+    }
 }
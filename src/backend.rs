@@ -0,0 +1,618 @@
+use {
+    crate::{
+        color::Style,
+        config::{self, Align, Config, Segment},
+        lines::LineReader,
+        markup::{escape_dzen2, escape_lemonbar},
+        monitor::Monitor,
+        signals::unblocked,
+    },
+    nix::{
+        sys::signal::{kill, Signal::SIGTERM},
+        unistd::Pid,
+    },
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::HashMap,
+        env,
+        fmt::{self, Write as FmtWrite},
+        fs,
+        io::{self, Write},
+        os::fd::{AsFd, OwnedFd},
+        path::PathBuf,
+        process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio},
+        str::FromStr,
+    },
+};
+
+/// Clicks on a bar.  Lines of `input` are clicked actions, or i3bar click events if
+/// `segments` is set.
+pub struct Clicks {
+    pub input: LineReader,
+    /// Segments of i3bar blocks by name
+    segments: Option<HashMap<String, ClickTarget>>,
+}
+
+/// How clicks on the i3bar block of a segment are handled.
+struct ClickTarget {
+    /// Action of left clicks
+    click: Option<String>,
+    /// Whether other clicks are forwarded to the active mode
+    mode: bool,
+}
+
+/// Header of the i3bar protocol.
+const I3BAR_HEADER: &str = r#"{"version":1,"click_events":true}"#;
+/// Set by a restarting server whose i3bar stream the new server continues.
+pub const I3BAR_CONTINUED: &str = "RUNEL_I3BAR_CONTINUED";
+
+/// The program the bar is drawn by.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Lemonbar,
+    Dzen2,
+    Stdout,
+    Tmux,
+    I3bar,
+}
+
+/// Formats segment values in the markup of a bar and writes them to it.
+pub trait Backend {
+    fn format(&self, segments: &[Segment], values: &[String], out: &mut String) -> fmt::Result;
+    fn write(&mut self, frame: &str) -> io::Result<()>;
+
+    /// Closes the bar when the server stops.
+    fn close(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The exit status of the bar program if it exited.  Bars which are not run by the
+    /// server never exit.
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        Ok(None)
+    }
+}
+
+/// A bar program reading frames from its stdin.
+struct Bar {
+    child: Child,
+    stdin: ChildStdin,
+}
+
+struct Lemonbar(Bar);
+struct Dzen2(Bar);
+struct Stdout(io::Stdout);
+struct Tmux(PathBuf);
+struct I3bar(io::Stdout);
+
+#[derive(Serialize)]
+struct Block<'a> {
+    name: &'a str,
+    full_text: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    color: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    background: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    border: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ClickEvent {
+    name: Option<String>,
+    button: u32,
+}
+
+impl BackendKind {
+    /// Whether the backend draws a bar per monitor.  Backends writing to stdout of the
+    /// server draw a single bar.
+    pub fn has_monitors(self) -> bool {
+        !matches!(self, Self::Stdout | Self::I3bar)
+    }
+
+    /// Whether the bar is a program run by the server, which can be started again.
+    pub fn is_program(self) -> bool {
+        matches!(self, Self::Lemonbar | Self::Dzen2)
+    }
+}
+
+/// Starts the output shared by all bars: the header of the i3bar protocol and the opening
+/// of its endless array with an empty first array, unless a restarted server continues it.
+pub fn begin(config: &Config) -> io::Result<()> {
+    let continued = env::var_os(I3BAR_CONTINUED).is_some();
+    env::remove_var(I3BAR_CONTINUED);
+    if config.backend == BackendKind::I3bar && !continued {
+        let mut stdout = io::stdout();
+        writeln!(stdout, "{}\n[\n[]", I3BAR_HEADER)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+/// Starts the backend for the monitor.  Bars which report clicks return them too.
+pub fn start(config: &Config, monitor: &Monitor) -> io::Result<(Box<dyn Backend>, Option<Clicks>)> {
+    let geometry = monitor.geometry.as_deref();
+
+    Ok(match config.backend {
+        BackendKind::Lemonbar => {
+            let geometry = geometry.map(|geometry| vec!["-g".into(), geometry.into()]);
+            let (bar, stdout) = spawn("lemonbar", &config.lemonbar_args, geometry)?;
+            (Box::new(Lemonbar(bar)), Some(Clicks::new(stdout, None)?))
+        }
+        BackendKind::Dzen2 => {
+            let geometry = geometry.map(dzen2_geometry);
+            let (bar, stdout) = spawn("dzen2", &config.dzen2_args, geometry)?;
+            (Box::new(Dzen2(bar)), Some(Clicks::new(stdout, None)?))
+        }
+        BackendKind::I3bar => {
+            let bar = I3bar(io::stdout());
+            let stdin = io::stdin().as_fd().try_clone_to_owned()?;
+            let clicks = Clicks::new(stdin, Some(i3bar_segments(&config.segments)))?;
+            (Box::new(bar), Some(clicks))
+        }
+        BackendKind::Stdout => (Box::new(Stdout(io::stdout())), None),
+        BackendKind::Tmux => {
+            let path = config.tmux_file.to_string_lossy();
+            let path = path.replace("{monitor}", &monitor.name);
+            (Box::new(Tmux(path.into())), None)
+        }
+    })
+}
+
+fn spawn(
+    program: &str,
+    args: &[String],
+    geometry: Option<Vec<String>>,
+) -> io::Result<(Bar, ChildStdout)> {
+    let mut child = unblocked(&mut Command::new(program))
+        .args(args)
+        .args(geometry.unwrap_or_default())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let stdin = child
+        .stdin
+        .take()
+        .ok_or_else(|| io::Error::other("No stdin of process"))?;
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| io::Error::other("No stdout of process"))?;
+
+    Ok((Bar { child, stdin }, stdout))
+}
+
+impl Clicks {
+    fn new(
+        input: impl Into<OwnedFd>,
+        segments: Option<HashMap<String, ClickTarget>>,
+    ) -> io::Result<Self> {
+        Ok(Self {
+            input: LineReader::new(input)?,
+            segments,
+        })
+    }
+
+    /// The action clicked in the line read from the bar.
+    pub fn action(&self, line: &str) -> Option<String> {
+        match &self.segments {
+            Some(segments) => parse_click(line, segments),
+            None => Some(line.into()),
+        }
+    }
+}
+
+/// Click events of i3bar come from stdin of the server.  A left click on a block runs the
+/// `click` action of its segment, other clicks on the mode segment are forwarded to the
+/// active mode as the JSON event.
+fn i3bar_segments(segments: &[Segment]) -> HashMap<String, ClickTarget> {
+    segments
+        .iter()
+        .map(|s| {
+            let target = ClickTarget {
+                click: s.click.clone(),
+                mode: s.mode,
+            };
+            (s.name.clone(), target)
+        })
+        .collect()
+}
+
+/// Parses an element of the endless array of click events.
+fn parse_click(line: &str, segments: &HashMap<String, ClickTarget>) -> Option<String> {
+    let event = line
+        .trim_start_matches(|c: char| c == '[' || c == ',' || c.is_whitespace())
+        .trim_end();
+    let click: ClickEvent = serde_json::from_str(event).ok()?;
+    let target = segments.get(&click.name?)?;
+    match &target.click {
+        Some(action) if click.button == 1 => Some(action.clone()),
+        _ if target.mode => Some(event.into()),
+        _ => None,
+    }
+}
+
+/// Translates lemonbar geometry `WxH+X+Y` to dzen2 options.
+fn dzen2_geometry(geometry: &str) -> Vec<String> {
+    let (size, position) = geometry.split_once('+').unwrap_or((geometry, ""));
+    let (width, height) = size.split_once('x').unwrap_or((size, ""));
+    let (x, y) = position.split_once('+').unwrap_or((position, ""));
+
+    [("-x", x), ("-y", y), ("-w", width), ("-h", height)]
+        .iter()
+        .filter(|(_, value)| !value.is_empty())
+        .flat_map(|(option, value)| vec![option.to_string(), value.to_string()])
+        .collect()
+}
+
+/// Segments with their values grouped by alignment.  Empty groups are skipped.
+fn groups<'a>(
+    segments: &'a [Segment],
+    values: &'a [String],
+) -> impl Iterator<Item = (Align, Vec<(&'a Segment, &'a str)>)> {
+    [Align::Left, Align::Center, Align::Right]
+        .iter()
+        .map(move |&align| {
+            let group = segments
+                .iter()
+                .zip(values)
+                .filter(|(segment, _)| segment.align == align)
+                .map(|(segment, value)| (segment, value.as_str()))
+                .collect::<Vec<_>>();
+            (align, group)
+        })
+        .filter(|(_, group)| !group.is_empty())
+}
+
+impl Backend for Lemonbar {
+    fn format(&self, segments: &[Segment], values: &[String], out: &mut String) -> fmt::Result {
+        for (align, group) in groups(segments, values) {
+            let tag = match align {
+                Align::Left => 'l',
+                Align::Center => 'c',
+                Align::Right => 'r',
+            };
+            write!(out, "%{{{}}}", tag)?;
+            for (segment, value) in group {
+                let value = escape_lemonbar(value, &segment.markup);
+                let value = segment.style.draw(value);
+                match &segment.click {
+                    Some(action) => write!(out, " %{{A:{}:}}{}%{{A}}", action, value)?,
+                    None => write!(out, " {}", value)?,
+                }
+            }
+            write!(out, " ")?;
+        }
+        writeln!(out)
+    }
+
+    fn write(&mut self, frame: &str) -> io::Result<()> {
+        self.0.write(frame)
+    }
+
+    fn close(self: Box<Self>) -> io::Result<()> {
+        self.0.close()
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.0.child.try_wait()
+    }
+}
+
+impl Bar {
+    fn write(&mut self, frame: &str) -> io::Result<()> {
+        self.stdin.write_all(frame.as_bytes())?;
+        self.stdin.flush()
+    }
+
+    /// Closes the stdin of the bar and terminates it, in case it is persistent.
+    fn close(mut self) -> io::Result<()> {
+        drop(self.stdin);
+        if let Ok(None) = self.child.try_wait() {
+            let _ = kill(Pid::from_raw(self.child.id() as i32), SIGTERM);
+        }
+        self.child.wait().map(|_| ())
+    }
+}
+
+/// Dzen2 has no alignment sections: the line is aligned as a whole with its `-ta` option.
+/// Clickable areas echo the action to its stdout.
+impl Backend for Dzen2 {
+    fn format(&self, segments: &[Segment], values: &[String], out: &mut String) -> fmt::Result {
+        for (_, group) in groups(segments, values) {
+            for (segment, value) in group {
+                write!(out, " ")?;
+                if let Some(action) = &segment.click {
+                    write!(out, "^ca(1,echo {})", action)?;
+                }
+                let value = escape_dzen2(value, &segment.markup);
+                let style = &segment.style;
+                match (style.foreground.rgb(), style.background.rgb()) {
+                    (Some(fg), Some(bg)) => {
+                        write!(out, "^fg({})^bg({}){}^bg()^fg()", fg, bg, value)?
+                    }
+                    (Some(fg), None) => write!(out, "^fg({}){}^fg()", fg, value)?,
+                    (None, Some(bg)) => write!(out, "^bg({}){}^bg()", bg, value)?,
+                    (None, None) => write!(out, "{}", value)?,
+                }
+                if segment.click.is_some() {
+                    write!(out, "^ca()")?;
+                }
+            }
+        }
+        writeln!(out)
+    }
+
+    fn write(&mut self, frame: &str) -> io::Result<()> {
+        self.0.write(frame)
+    }
+
+    fn close(self: Box<Self>) -> io::Result<()> {
+        self.0.close()
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        self.0.child.try_wait()
+    }
+}
+
+/// Plain non-empty values separated by spaces.
+impl Backend for Stdout {
+    fn format(&self, segments: &[Segment], values: &[String], out: &mut String) -> fmt::Result {
+        let values = groups(segments, values)
+            .flat_map(|(_, group)| group)
+            .filter(|(_, value)| !value.is_empty());
+        for (i, (_, value)) in values.enumerate() {
+            if i > 0 {
+                write!(out, " ")?;
+            }
+            write!(out, "{}", value)?;
+        }
+        writeln!(out)
+    }
+
+    fn write(&mut self, frame: &str) -> io::Result<()> {
+        let mut stdout = self.0.lock();
+        stdout.write_all(frame.as_bytes())?;
+        stdout.flush()
+    }
+}
+
+/// Tmux status format, to be included with `#(cat <file>)`.  The file is replaced
+/// atomically, so tmux never reads a partial frame.
+impl Backend for Tmux {
+    fn format(&self, segments: &[Segment], values: &[String], out: &mut String) -> fmt::Result {
+        for (align, group) in groups(segments, values) {
+            let align = match align {
+                Align::Left => "left",
+                Align::Center => "centre",
+                Align::Right => "right",
+            };
+            write!(out, "#[align={}]", align)?;
+            for (segment, value) in group {
+                let value = value.replace('#', "##");
+                write!(out, " ")?;
+                let style = tmux_style(&segment.style);
+                if style.is_empty() {
+                    write!(out, "{}", value)?;
+                } else {
+                    write!(out, "#[{}]{}#[default]", style.join(","), value)?;
+                }
+            }
+            write!(out, " ")?;
+        }
+        writeln!(out)
+    }
+
+    fn write(&mut self, frame: &str) -> io::Result<()> {
+        let mut tmp = self.0.clone().into_os_string();
+        tmp.push(".tmp");
+        config::no_follow()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)?
+            .write_all(frame.as_bytes())?;
+        fs::rename(&tmp, &self.0)
+    }
+}
+
+/// Attributes of `#[...]` for the style.  Tmux has no alpha and no separate line color.
+fn tmux_style(style: &Style) -> Vec<String> {
+    let mut attributes = Vec::new();
+    if let Some(fg) = style.foreground.rgb() {
+        attributes.push(format!("fg={}", fg));
+    }
+    if let Some(bg) = style.background.rgb() {
+        attributes.push(format!("bg={}", bg));
+    }
+    for (set, attribute) in &[
+        (style.underline, "underscore"),
+        (style.overline, "overline"),
+        (style.reverse, "reverse"),
+    ] {
+        if *set {
+            attributes.push(attribute.to_string());
+        }
+    }
+    attributes
+}
+
+/// Blocks of non-empty segments, one array per line.  Arrays are preceded by a comma, as the
+/// whole stream is an endless JSON array started by `begin`.
+impl Backend for I3bar {
+    fn format(&self, segments: &[Segment], values: &[String], out: &mut String) -> fmt::Result {
+        let blocks: Vec<Block> = groups(segments, values)
+            .flat_map(|(_, group)| group)
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(segment, value)| Block {
+                name: &segment.name,
+                full_text: value,
+                color: segment.style.foreground.rgba(),
+                background: segment.style.background.rgba(),
+                border: segment.style.line.rgba(),
+            })
+            .collect();
+
+        let blocks = serde_json::to_string(&blocks).map_err(|_| fmt::Error)?;
+        writeln!(out, ",{}", blocks)
+    }
+
+    fn write(&mut self, frame: &str) -> io::Result<()> {
+        let mut stdout = self.0.lock();
+        stdout.write_all(frame.as_bytes())?;
+        stdout.flush()
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lemonbar" => Ok(Self::Lemonbar),
+            "dzen2" => Ok(Self::Dzen2),
+            "stdout" => Ok(Self::Stdout),
+            "tmux" => Ok(Self::Tmux),
+            "i3bar" => Ok(Self::I3bar),
+            _ => Err(format!("Unknown backend: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let backend = match self {
+            Self::Lemonbar => "lemonbar",
+            Self::Dzen2 => "dzen2",
+            Self::Stdout => "stdout",
+            Self::Tmux => "tmux",
+            Self::I3bar => "i3bar",
+        };
+        f.write_str(backend)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments(toml: &str) -> Vec<Segment> {
+        toml::from_str::<Config>(toml).unwrap().segments
+    }
+
+    fn format(backend: &dyn Backend, segments: &[Segment], values: &[&str]) -> String {
+        let values: Vec<String> = values.iter().map(|&v| v.into()).collect();
+        let mut out = String::new();
+        backend.format(segments, &values, &mut out).unwrap();
+        out
+    }
+
+    /// A bar program which is never written to.
+    fn bar() -> Bar {
+        spawn("cat", &[], None).unwrap().0
+    }
+
+    const SEGMENTS: &str = r##"
+        [[segment]]
+        name = "right"
+        command = ["date"]
+        align = "right"
+        style = { foreground = "#f00", underline = true }
+        [[segment]]
+        name = "plain"
+        command = ["a"]
+        [[segment]]
+        name = "click"
+        command = ["b"]
+        click = "menu"
+        style = { background = "#00f" }
+        [actions]
+        menu = "next"
+    "##;
+
+    #[test]
+    fn formats_lemonbar() {
+        let lemonbar = Lemonbar(bar());
+        assert_eq!(
+            format(&lemonbar, &segments(SEGMENTS), &["12:00", "a%b", "#c"]),
+            "%{l} a%%b %{A:menu:}%{B#0000FF}#c%{B-}%{A} \
+             %{r} %{F#FF0000}%{+u}12:00%{-u}%{F-} \n"
+        );
+        Box::new(lemonbar).close().unwrap();
+    }
+
+    #[test]
+    fn formats_dzen2() {
+        let dzen2 = Dzen2(bar());
+        assert_eq!(
+            format(&dzen2, &segments(SEGMENTS), &["12:00", "a^b", "#c"]),
+            " a^^b ^ca(1,echo menu)^bg(#0000FF)#c^bg()^ca() ^fg(#FF0000)12:00^fg()\n"
+        );
+        Box::new(dzen2).close().unwrap();
+    }
+
+    #[test]
+    fn formats_tmux() {
+        let tmux = Tmux(PathBuf::new());
+        assert_eq!(
+            format(&tmux, &segments(SEGMENTS), &["12:00", "a#b", "#[c]"]),
+            "#[align=left] a##b #[bg=#0000FF]##[c]#[default] \
+             #[align=right] #[fg=#FF0000,underscore]12:00#[default] \n"
+        );
+    }
+
+    #[test]
+    fn formats_i3bar() {
+        let segments = segments(SEGMENTS);
+        let blocks = r##"[{"name":"click","full_text":"c","background":"#0000FFFF"},"##.to_string()
+            + r##"{"name":"right","full_text":"12:00","color":"#FF0000FF"}]"##;
+        let i3bar = I3bar(io::stdout());
+        assert_eq!(
+            format(&i3bar, &segments, &["12:00", "", "c"]),
+            format!(",{}\n", blocks)
+        );
+    }
+
+    #[test]
+    fn parses_i3bar_clicks() {
+        let toml = format!("{}\n[[segment]]\nname = \"mode\"\nmode = true", SEGMENTS);
+        let segments = i3bar_segments(&segments(&toml));
+        let click = |line| parse_click(line, &segments);
+
+        let first = r#"[{"name":"click","instance":"0","button":1,"x":10}"#;
+        assert_eq!(click(first).as_deref(), Some("menu"));
+        assert_eq!(
+            click(r#",{"name":"click","button":1}"#).as_deref(),
+            Some("menu")
+        );
+        assert_eq!(click(r#",{"name":"click","button":3}"#), None);
+        assert_eq!(click(r#",{"name":"plain","button":1}"#), None);
+        assert_eq!(click(r#",{"button":1}"#), None);
+        assert_eq!(click(r#",{"name":"unknown","button":1}"#), None);
+        assert_eq!(click(r#",{"name":"click","button":"#), None);
+        assert_eq!(click("["), None);
+
+        let mode = r#",{"name":"mode","button":4} "#;
+        assert_eq!(
+            click(mode).as_deref(),
+            Some(r#"{"name":"mode","button":4}"#)
+        );
+    }
+
+    #[test]
+    fn translates_dzen2_geometry() {
+        assert_eq!(
+            dzen2_geometry("1920x20+0+0"),
+            ["-x", "0", "-y", "0", "-w", "1920", "-h", "20"]
+        );
+        assert_eq!(
+            dzen2_geometry("1280x+1920+56"),
+            ["-x", "1920", "-y", "56", "-w", "1280"]
+        );
+        assert_eq!(dzen2_geometry("800x600"), ["-w", "800", "-h", "600"]);
+    }
+}
@@ -1,24 +1,259 @@
 use {
     crate::{
-        config::{MAX_MSG_LEN, MQUEUE},
-        mode::Mode,
+        config::{self, Config, Ipc},
+        ipc::{self, Reply, Request, Status, REPLY_MSG_LEN},
+        mode::{self, Mode},
+    },
+    posixmq::{unlink, OpenOptions},
+    std::{
+        env,
+        error::Error,
+        ffi::OsString,
+        io::{
+            self,
+            ErrorKind::{
+                ConnectionRefused, InvalidData, InvalidInput, NotFound, TimedOut, WouldBlock,
+            },
+        },
+        os::unix::net::UnixStream,
+        process::{self, Child, Command, Stdio},
+        thread,
+        time::{Duration, Instant},
     },
-    posixmq::OpenOptions,
-    std::io::ErrorKind::{NotFound, WouldBlock},
 };
 
-pub fn run(mode: Mode) -> std::io::Result<()> {
-    let mq = match OpenOptions::writeonly().nonblocking().open(MQUEUE) {
-        Ok(mq) => mq,
-        Err(e) if e.kind() == NotFound => return Ok(()),
-        Err(e) => return Err(e),
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often a request is retried while waiting for the server.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/// How long an autostarted server is given to listen to requests.
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What to do when the server is not running or its message queue is full.  By default
+/// the request is dropped.
+#[derive(Default)]
+pub struct Delivery {
+    /// Fail instead of dropping the request
+    pub strict: bool,
+    /// Retry until the server takes the request
+    pub wait: bool,
+    /// Start the server with these options if it is not running
+    pub autostart: Option<Vec<OsString>>,
+}
+
+pub fn run(
+    config: &Config,
+    monitor: Option<&str>,
+    mode: Mode,
+    delivery: &Delivery,
+) -> std::io::Result<()> {
+    send(
+        config,
+        monitor,
+        Request::Mode(mode.mode, mode.args),
+        delivery,
+    )
+}
+
+pub fn status(
+    config: &Config,
+    monitor: Option<&str>,
+    json: bool,
+    delivery: &Delivery,
+) -> Result<(), Box<dyn Error>> {
+    let status = query(config, monitor, delivery)?;
+
+    if json {
+        println!("{}", serde_json::to_string(&status)?);
+    } else {
+        println!("{}", status);
+    }
+    Ok(())
+}
+
+pub fn list_modes(
+    config: &Config,
+    monitor: Option<&str>,
+    delivery: &Delivery,
+) -> Result<(), Box<dyn Error>> {
+    let active = query(config, monitor, delivery)
+        .ok()
+        .map(|status| status.mode);
+
+    for info in mode::list(&config.mode_dir)? {
+        let marker = if active.as_ref() == Some(&info.mode) {
+            '*'
+        } else {
+            ' '
+        };
+        match info.description {
+            Some(description) => println!("{} {}\t{}", marker, info.mode, description),
+            None => println!("{} {}", marker, info.mode),
+        }
+    }
+    Ok(())
+}
+
+fn query(
+    config: &Config,
+    monitor: Option<&str>,
+    delivery: &Delivery,
+) -> Result<Status, Box<dyn Error>> {
+    if config.ipc == Ipc::Socket {
+        return match deliver(config, monitor, &Request::Status(String::new()), delivery) {
+            Ok(Reply::Status(status)) => Ok(status),
+            Ok(reply) => Err(format!("Unexpected reply {:?}", reply).into()),
+            Err(e) if e.kind() == NotFound => Err("Server is not running".into()),
+            Err(e) => Err(e.into()),
+        };
+    }
+
+    let reply_name = format!("{}-{}", config.mqueue, process::id());
+    let reply_mq = OpenOptions::readonly()
+        .max_msg_len(REPLY_MSG_LEN)
+        .capacity(1)
+        .create_new()
+        .open(&reply_name)?;
+    let mut buf = vec![0; REPLY_MSG_LEN];
+
+    let reply = deliver(
+        config,
+        monitor,
+        &Request::Status(reply_name.clone()),
+        delivery,
+    )
+    .and_then(|_| reply_mq.receive_timeout(&mut buf, REPLY_TIMEOUT));
+    unlink(&reply_name)?;
+
+    match reply {
+        Ok((_, len)) => match serde_json::from_slice(&buf[..len])? {
+            Reply::Status(status) => Ok(status),
+            Reply::Error(e) => Err(e.into()),
+            reply => Err(format!("Unexpected reply {:?}", reply).into()),
+        },
+        Err(e) if e.kind() == TimedOut => Err("Server does not reply".into()),
+        Err(e) if e.kind() == NotFound => Err("Server is not running".into()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn send(
+    config: &Config,
+    monitor: Option<&str>,
+    request: Request,
+    delivery: &Delivery,
+) -> std::io::Result<()> {
+    match deliver(config, monitor, &request, delivery) {
+        Err(e) if e.kind() == NotFound && delivery.strict => {
+            Err(io::Error::new(NotFound, "Server is not running"))
+        }
+        Err(e) if e.kind() == WouldBlock && delivery.strict => {
+            Err(io::Error::new(WouldBlock, "Message queue is full"))
+        }
+        Err(e) if e.kind() == NotFound || e.kind() == WouldBlock => Ok(()),
+        other => other.map(|_| ()),
+    }
+}
+
+/// Sends the request, waiting for the server or starting it as `delivery` tells.
+fn deliver(
+    config: &Config,
+    monitor: Option<&str>,
+    request: &Request,
+    delivery: &Delivery,
+) -> io::Result<Reply> {
+    let mut server: Option<(Child, Instant)> = None;
+    loop {
+        let e = match send_to(config, monitor, request) {
+            Err(e) => e,
+            reply => return reply,
+        };
+
+        match (&delivery.autostart, &mut server) {
+            (Some(args), None) if e.kind() == NotFound => {
+                server = Some((start(config, request, args)?, Instant::now()));
+            }
+            (Some(_), Some((child, started))) if e.kind() == NotFound => {
+                if let Some(status) = child.try_wait()?.filter(|status| !status.success()) {
+                    return Err(io::Error::other(format!("Server exited with {}", status)));
+                } else if started.elapsed() > START_TIMEOUT && !delivery.wait {
+                    let e = format!("Server did not start in {:?}", START_TIMEOUT);
+                    return Err(io::Error::new(TimedOut, e));
+                }
+            }
+            _ if delivery.wait && (e.kind() == NotFound || e.kind() == WouldBlock) => (),
+            _ => return Err(e),
+        }
+        thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// Starts the server in the background with the requested mode, or with the first mode
+/// of the ring for other requests.
+fn start(config: &Config, request: &Request, args: &[OsString]) -> io::Result<Child> {
+    let mode = match request {
+        Request::Mode(mode, _) => mode,
+        _ => config.modes.first().ok_or_else(|| {
+            let e = "Server is not running and `modes` is empty, so it has no mode to start";
+            io::Error::new(InvalidInput, e)
+        })?,
     };
 
-    let mut mode = mode.mode;
-    mode.truncate(MAX_MSG_LEN);
+    Command::new(env::current_exe()?)
+        .args(["--server", "--mode", mode])
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()
+}
 
-    match mq.send(0, mode.as_bytes()) {
-        Err(e) if e.kind() == WouldBlock => Ok(()),
-        other => other,
+/// Sends the request to the server.  Only the socket carries replies; errors replied by
+/// the server are returned as errors.
+fn send_to(config: &Config, monitor: Option<&str>, request: &Request) -> io::Result<Reply> {
+    let buf = request
+        .encode(monitor)
+        .map_err(|e| io::Error::new(InvalidInput, e))?;
+    if buf.len() > config.max_msg_len {
+        let e = format!(
+            "Request of {} bytes is longer than max_msg_len ({})",
+            buf.len(),
+            config.max_msg_len
+        );
+        return Err(io::Error::new(InvalidInput, e));
     }
+
+    if config.ipc == Ipc::Socket {
+        return match exchange(config, &buf)? {
+            Reply::Error(e) => Err(io::Error::other(e)),
+            reply => Ok(reply),
+        };
+    }
+
+    let mq = OpenOptions::writeonly()
+        .nonblocking()
+        .open(&config.mqueue)?;
+    mq.send(0, &buf).map(|_| Reply::Done)
+}
+
+fn exchange(config: &Config, request: &[u8]) -> io::Result<Reply> {
+    // Another user could listen on a socket in a runtime directory they own
+    let runtime_dir = config::runtime_dir();
+    if config.socket.starts_with(&runtime_dir) {
+        config::check_runtime_dir(&runtime_dir).map_err(io::Error::other)?;
+    }
+    let mut stream = match UnixStream::connect(&config.socket) {
+        // A socket left by a server which did not stop cleanly
+        Err(e) if e.kind() == ConnectionRefused => return Err(NotFound.into()),
+        stream => stream?,
+    };
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    stream.set_write_timeout(Some(REPLY_TIMEOUT))?;
+
+    ipc::write_frame(&mut stream, request)?;
+    let reply = match ipc::read_frame(&mut stream, REPLY_MSG_LEN) {
+        Err(e) if e.kind() == WouldBlock => {
+            return Err(io::Error::new(TimedOut, "Server does not reply"))
+        }
+        reply => reply?,
+    };
+    serde_json::from_slice(&reply).map_err(|e| io::Error::new(InvalidData, e))
 }
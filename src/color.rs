@@ -1,14 +1,114 @@
-use std::{
-    fmt::{self, Display},
-    str::FromStr,
+use {
+    serde::{de, Deserialize, Deserializer},
+    std::{
+        fmt::{self, Display},
+        str::FromStr,
+    },
 };
 
-pub struct Color(Option<String>);
-pub struct DrawColor<'a, D: Display>(&'a Color, D);
+/// A color in one of the lemonbar formats: `#RGB`, `#RRGGBB` or `#AARRGGBB`.  The empty
+/// string is no color.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Color(Option<Argb>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Argb {
+    alpha: u8,
+    red: u8,
+    green: u8,
+    blue: u8,
+}
+
+/// Lemonbar formatting of an element.  Unset fields keep the formatting of the bar.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Style {
+    pub foreground: Color,
+    pub background: Color,
+    /// Color of underline and overline
+    pub line: Color,
+    pub underline: bool,
+    pub overline: bool,
+    /// Swap foreground and background
+    pub reverse: bool,
+    /// Index of the lemonbar font, starting at 1
+    pub font: Option<u8>,
+    /// Offset in pixels before the element
+    pub offset: Option<i32>,
+}
+
+/// A lemonbar formatting tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tag {
+    Foreground(Color),
+    Background(Color),
+    Line(Color),
+    Underline(bool),
+    Overline(bool),
+    Reverse,
+    Font(Option<u8>),
+    Offset(i32),
+}
+
+pub struct DrawStyle<'a, D: Display>(&'a Style, D);
 
 impl Color {
-    pub fn draw<D: Display>(&self, element: D) -> DrawColor<D> {
-        DrawColor(self, element)
+    pub fn is_none(&self) -> bool {
+        self.0.is_none()
+    }
+
+    /// `#RRGGBB`, without alpha.
+    pub fn rgb(&self) -> Option<String> {
+        self.0
+            .map(|c| format!("#{:02X}{:02X}{:02X}", c.red, c.green, c.blue))
+    }
+
+    /// `#RRGGBBAA`, as i3bar wants it.
+    pub fn rgba(&self) -> Option<String> {
+        self.0
+            .map(|c| format!("#{:02X}{:02X}{:02X}{:02X}", c.red, c.green, c.blue, c.alpha))
+    }
+}
+
+impl Style {
+    pub fn draw<D: Display>(&self, element: D) -> DrawStyle<'_, D> {
+        DrawStyle(self, element)
+    }
+
+    /// Tags enabling the style and tags restoring the formatting of the bar.
+    pub fn tags(&self) -> (Vec<Tag>, Vec<Tag>) {
+        let (mut open, mut close) = (Vec::new(), Vec::new());
+        let mut tag = |set: bool, on: Tag, off: Tag| {
+            if set {
+                open.push(on);
+                close.insert(0, off);
+            }
+        };
+
+        tag(self.font.is_some(), Tag::Font(self.font), Tag::Font(None));
+        tag(
+            !self.foreground.is_none(),
+            Tag::Foreground(self.foreground),
+            Tag::Foreground(Color::default()),
+        );
+        tag(
+            !self.background.is_none(),
+            Tag::Background(self.background),
+            Tag::Background(Color::default()),
+        );
+        tag(
+            !self.line.is_none(),
+            Tag::Line(self.line),
+            Tag::Line(Color::default()),
+        );
+        tag(self.underline, Tag::Underline(true), Tag::Underline(false));
+        tag(self.overline, Tag::Overline(true), Tag::Overline(false));
+        tag(self.reverse, Tag::Reverse, Tag::Reverse);
+
+        if let Some(offset) = self.offset {
+            open.insert(0, Tag::Offset(offset));
+        }
+        (open, close)
     }
 }
 
@@ -17,30 +117,155 @@ impl FromStr for Color {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s.is_empty() {
-            Ok(Self(None))
-        } else if s.len() == 7
-            && s.starts_with("#")
-            && s.chars().skip(1).all(|c| c.is_ascii_hexdigit())
-        {
-            Ok(Self(Some(s.into())))
-        } else {
-            Err(format!("Invalid hex color: {}", s))
+            return Ok(Self(None));
         }
+
+        let error = || format!("Invalid hex color: {}", s);
+        let hex = s
+            .strip_prefix('#')
+            .filter(|hex| hex.chars().all(|c| c.is_ascii_hexdigit()))
+            .ok_or_else(error)?;
+        let value = u32::from_str_radix(hex, 16).map_err(|_| error())?;
+        let byte = |shift: u32| (value >> shift) as u8;
+        // Each digit of #RGB is doubled: #F80 is #FF8800
+        let digit = |shift: u32| ((value >> shift) & 0xF) as u8 * 0x11;
+
+        let argb = match hex.len() {
+            3 => Argb {
+                alpha: 0xFF,
+                red: digit(8),
+                green: digit(4),
+                blue: digit(0),
+            },
+            6 => Argb {
+                alpha: 0xFF,
+                red: byte(16),
+                green: byte(8),
+                blue: byte(0),
+            },
+            8 => Argb {
+                alpha: byte(24),
+                red: byte(16),
+                green: byte(8),
+                blue: byte(0),
+            },
+            _ => return Err(error()),
+        };
+        Ok(Self(Some(argb)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
     }
 }
 
-impl<'a, D: Display> Display for DrawColor<'a, D> {
+/// Lemonbar format: `#RRGGBB` for opaque colors, `#AARRGGBB` otherwise, and `-` for no
+/// color, which restores the color of the bar.
+impl Display for Color {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(c) = &(self.0).0 {
-            write!(f, "%{{F{}}}{}%{{F-}}", c, self.1)
-        } else {
-            write!(f, "{}", self.1)
+        match self.0 {
+            Some(c) if c.alpha == 0xFF => {
+                write!(f, "#{:02X}{:02X}{:02X}", c.red, c.green, c.blue)
+            }
+            Some(c) => write!(
+                f,
+                "#{:02X}{:02X}{:02X}{:02X}",
+                c.alpha, c.red, c.green, c.blue
+            ),
+            None => write!(f, "-"),
         }
     }
 }
 
-impl AsRef<Option<String>> for Color {
-    fn as_ref(&self) -> &Option<String> {
-        &self.0
+impl Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Foreground(color) => write!(f, "%{{F{}}}", color),
+            Self::Background(color) => write!(f, "%{{B{}}}", color),
+            Self::Line(color) => write!(f, "%{{U{}}}", color),
+            Self::Underline(on) => write!(f, "%{{{}u}}", if *on { '+' } else { '-' }),
+            Self::Overline(on) => write!(f, "%{{{}o}}", if *on { '+' } else { '-' }),
+            Self::Reverse => write!(f, "%{{R}}"),
+            Self::Font(Some(font)) => write!(f, "%{{T{}}}", font),
+            Self::Font(None) => write!(f, "%{{T-}}"),
+            Self::Offset(offset) => write!(f, "%{{O{}}}", offset),
+        }
+    }
+}
+
+impl<'a, D: Display> Display for DrawStyle<'a, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (open, close) = self.0.tags();
+        for tag in open {
+            write!(f, "{}", tag)?;
+        }
+        write!(f, "{}", self.1)?;
+        for tag in close {
+            write!(f, "{}", tag)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn color(s: &str) -> Color {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn doubles_short_hex_digits() {
+        assert_eq!(color("#F80"), color("#FF8800"));
+        assert_eq!(color("#f80").to_string(), "#FF8800");
+    }
+
+    #[test]
+    fn reads_alpha_first() {
+        let c = color("#80102030");
+        assert_eq!(
+            c.0,
+            Some(Argb {
+                alpha: 0x80,
+                red: 0x10,
+                green: 0x20,
+                blue: 0x30,
+            })
+        );
+    }
+
+    #[test]
+    fn prints_alpha_only_if_translucent() {
+        assert_eq!(color("#FF102030").to_string(), "#102030");
+        assert_eq!(color("#80102030").to_string(), "#80102030");
+        assert_eq!(color("").to_string(), "-");
+    }
+
+    #[test]
+    fn reorders_alpha_for_i3bar() {
+        assert_eq!(color("#80102030").rgba().unwrap(), "#10203080");
+        assert_eq!(color("#102030").rgba().unwrap(), "#102030FF");
+        assert_eq!(color("#80102030").rgb().unwrap(), "#102030");
+        assert_eq!(color("").rgba(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_colors() {
+        for s in &[
+            "102030",
+            "#12",
+            "#12345",
+            "#1234567",
+            "#GGGGGG",
+            "#+12",
+            "#ＦＦＦ",
+        ] {
+            assert!(s.parse::<Color>().is_err(), "{}", s);
+        }
     }
 }
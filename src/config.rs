@@ -1,7 +1,675 @@
-pub const WMSTATUS_CMD: &'static [&'static str] = &["wmstatus"];
-pub const TITLE_CMD: &'static [&'static str] = &["xtitle", "-siet170"];
+use {
+    crate::{
+        backend::BackendKind,
+        color::{Color, Style},
+        log::Level,
+        markup::{self, ALL_TAGS},
+        monitor::Monitor,
+    },
+    nix::{fcntl::OFlag, unistd::getuid},
+    serde::Deserialize,
+    std::{
+        collections::HashMap,
+        env,
+        ffi::OsString,
+        fmt,
+        fs::{self, DirBuilder, OpenOptions},
+        io::ErrorKind::NotFound,
+        os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+        path::{Path, PathBuf},
+        str::FromStr,
+    },
+    structopt::StructOpt,
+};
 
-pub const MQUEUE: &'static str = "/runel";
-pub const MAX_MSG_LEN: usize = 255;
-pub const CAPACITY: usize = 10;
-pub const CONFIG_DIR: &'static str = "runel";
+pub const CONFIG_DIR: &str = "runel";
+pub const CONFIG_FILE: &str = "config.toml";
+const TMUX_FILE: &str = "runel.tmux";
+const SOCKET: &str = "runel.sock";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(rename = "segment")]
+    pub segments: Vec<Segment>,
+    pub actions: HashMap<String, Action>,
+    #[serde(rename = "monitor")]
+    pub monitors: Vec<Monitor>,
+    pub detect_monitors: bool,
+    pub modes: Vec<String>,
+    pub history_size: usize,
+    pub supervisor: Supervisor,
+    pub ipc: Ipc,
+    pub mqueue: String,
+    pub max_msg_len: usize,
+    pub capacity: usize,
+    pub socket: PathBuf,
+    pub mode_dir: PathBuf,
+    /// Directory of the config file
+    #[serde(skip)]
+    pub config_dir: PathBuf,
+    pub backend: BackendKind,
+    pub lemonbar_args: Vec<String>,
+    pub dzen2_args: Vec<String>,
+    pub tmux_file: PathBuf,
+    /// Minimum time between renders of the bar; updates in between are coalesced
+    pub render_interval_ms: u64,
+    pub log_level: Level,
+    /// Size of the log in bytes, after which it is rotated
+    pub log_max_size: u64,
+    /// Number of rotated logs to keep
+    pub log_files: usize,
+}
+
+/// A named part of the bar.  Its value is taken either from the stdout of `command` or
+/// from the active mode.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Segment {
+    pub name: String,
+    #[serde(default)]
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub mode: bool,
+    #[serde(default)]
+    pub align: Align,
+    /// Shorthand for `style.foreground`
+    #[serde(default)]
+    pub color: Color,
+    #[serde(default)]
+    pub style: Style,
+    /// Lemonbar tags the source may emit.  Markup of untrusted segments is escaped
+    #[serde(default)]
+    pub markup: Vec<String>,
+    #[serde(default)]
+    pub click: Option<String>,
+    #[serde(default)]
+    pub restart: Restart,
+    /// Run the command once per monitor, with `{monitor}` replaced by the monitor name
+    #[serde(default)]
+    pub per_monitor: bool,
+}
+
+/// When to restart a segment command after it exits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Restart {
+    #[default]
+    Always,
+    OnFailure,
+    Never,
+}
+
+/// Restart settings of segment commands.  The delay before a restart starts at `delay_ms`
+/// and doubles up to `max_delay_ms`.  A command that ran for at least `max_delay_ms` is
+/// considered stable, and its delay and restart count are reset.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Supervisor {
+    pub delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub max_restarts: usize,
+    pub down_marker: String,
+}
+
+/// What to do when a clickable area with the action name is clicked in lemonbar.  Clicks
+/// on areas without a configured action are forwarded to stdin of the active mode.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub enum Action {
+    /// Switch to the mode
+    Mode(String),
+    /// Switch to the mode following the active one in the list
+    Cycle(Vec<String>),
+    /// Switch to the next mode in the ring
+    Next,
+    /// Switch to the previous mode in the ring
+    Prev,
+    /// Write the line to stdin of the active mode
+    Send(String),
+    /// Run the command with `sh -c`
+    Shell(String),
+}
+
+/// How clients talk to the server: through the POSIX message queue `mqueue` or the Unix
+/// socket `socket`, which also carries replies.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ipc {
+    #[default]
+    Mqueue,
+    Socket,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Align {
+    #[default]
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(StructOpt)]
+pub struct Overrides {
+    #[structopt(short, long, name = "CONFIG", parse(from_os_str))]
+    /// Config file [default: $XDG_CONFIG_HOME/runel/config.toml]
+    config: Option<PathBuf>,
+    #[structopt(long, name = "MQUEUE")]
+    /// Name of POSIX message queue
+    mqueue: Option<String>,
+    #[structopt(long = "max-msg-len", name = "MAX_MSG_LEN")]
+    /// Maximum length of a message
+    max_msg_len: Option<usize>,
+    #[structopt(long, name = "CAPACITY")]
+    /// Capacity of message queue
+    capacity: Option<usize>,
+    #[structopt(long = "mode-dir", name = "MODE_DIR", parse(from_os_str))]
+    /// Directory with mode executables
+    mode_dir: Option<PathBuf>,
+    #[structopt(long, name = "BACKEND")]
+    /// Program drawing the bar: lemonbar, dzen2, stdout, tmux or i3bar
+    backend: Option<BackendKind>,
+    #[structopt(long = "log-level", name = "LOG_LEVEL")]
+    /// Log level of the server: error, warn, info or debug
+    log_level: Option<Level>,
+    #[structopt(long = "segment-cmd", name = "NAME=COMMAND", number_of_values = 1)]
+    /// Command of the segment, split on whitespace (can be repeated)
+    segment_cmd: Vec<SegmentCommand>,
+}
+
+/// A command line split on whitespace for the segment with the name.
+pub struct SegmentCommand {
+    name: String,
+    command: Vec<String>,
+}
+
+impl Overrides {
+    /// Makes the paths absolute, as the server changes its working directory when it
+    /// detaches and reads the config again on reload.
+    pub fn make_absolute(&mut self) -> Result<(), String> {
+        if let Some(path) = &mut self.config {
+            // A missing file is reported by `Config::load`
+            if let Ok(canonical) = fs::canonicalize(&path) {
+                *path = canonical;
+            }
+        }
+        if let Some(path) = &mut self.mode_dir {
+            *path = std::path::absolute(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Command line options which give the same overrides.
+    pub fn args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = Vec::new();
+        let mut push = |option: &str, value: OsString| args.extend([option.into(), value]);
+
+        if let Some(config) = &self.config {
+            push("--config", config.into());
+        }
+        if let Some(mqueue) = &self.mqueue {
+            push("--mqueue", mqueue.into());
+        }
+        if let Some(max_msg_len) = self.max_msg_len {
+            push("--max-msg-len", max_msg_len.to_string().into());
+        }
+        if let Some(capacity) = self.capacity {
+            push("--capacity", capacity.to_string().into());
+        }
+        if let Some(mode_dir) = &self.mode_dir {
+            push("--mode-dir", mode_dir.into());
+        }
+        if let Some(backend) = self.backend {
+            push("--backend", backend.to_string().into());
+        }
+        if let Some(log_level) = self.log_level {
+            push("--log-level", log_level.to_string().to_lowercase().into());
+        }
+        for segment_cmd in &self.segment_cmd {
+            push("--segment-cmd", segment_cmd.to_string().into());
+        }
+        args
+    }
+}
+
+impl FromStr for SegmentCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, command) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected NAME=COMMAND: {}", s))?;
+        let command: Vec<String> = command.split_whitespace().map(String::from).collect();
+        if name.is_empty() || command.is_empty() {
+            return Err(format!("Expected NAME=COMMAND: {}", s));
+        }
+        Ok(Self {
+            name: name.into(),
+            command,
+        })
+    }
+}
+
+impl fmt::Display for SegmentCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.command.join(" "))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let mut mode_dir = dirs::config_dir().unwrap_or_default();
+        mode_dir.push(CONFIG_DIR);
+        let config_dir = mode_dir.clone();
+        let mut tmux_file = runtime_dir();
+        tmux_file.push(TMUX_FILE);
+        let socket = runtime_dir().join(SOCKET);
+
+        Self {
+            segments: vec![
+                Segment::command("wmstatus", &["wmstatus"], Align::Left),
+                Segment::command("title", &["xtitle", "-siet170"], Align::Left),
+                Segment {
+                    mode: true,
+                    ..Segment::command("mode", &[], Align::Right)
+                },
+            ],
+            actions: HashMap::new(),
+            monitors: Vec::new(),
+            detect_monitors: false,
+            modes: Vec::new(),
+            history_size: 10,
+            supervisor: Supervisor::default(),
+            ipc: Ipc::default(),
+            mqueue: "/runel".into(),
+            max_msg_len: 255,
+            capacity: 10,
+            socket,
+            mode_dir,
+            config_dir,
+            backend: BackendKind::default(),
+            lemonbar_args: Vec::new(),
+            dzen2_args: Vec::new(),
+            tmux_file,
+            render_interval_ms: 50,
+            log_level: Level::default(),
+            log_max_size: 1 << 20,
+            log_files: 3,
+        }
+    }
+}
+
+impl Default for Supervisor {
+    fn default() -> Self {
+        Self {
+            delay_ms: 1000,
+            max_delay_ms: 60_000,
+            max_restarts: 10,
+            down_marker: "[down]".into(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads config from `path` or from the default location and applies command line
+    /// overrides.  A missing default config is not an error: built-in values are used.
+    pub fn load(overrides: &Overrides) -> Result<Self, String> {
+        let mut config = match &overrides.config {
+            Some(path) => Self::read(path)?
+                .ok_or_else(|| format!("Config file \"{}\" does not exist", path.display()))?,
+            None => match default_path() {
+                Some(path) => Self::read(&path)?.unwrap_or_default(),
+                None => Self::default(),
+            },
+        };
+
+        if let Some(path) = &overrides.config {
+            let path = fs::canonicalize(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            config.config_dir = path.parent().unwrap_or(&path).into();
+        }
+        config.apply(overrides)?;
+        config.validate()?;
+        for segment in &mut config.segments {
+            if !segment.color.is_none() {
+                segment.style.foreground = segment.color;
+            }
+        }
+        Ok(config)
+    }
+
+    /// The pidfile of the server is named after the message queue or socket it listens on,
+    /// so servers with different endpoints can run side by side.
+    pub fn pidfile(&self) -> PathBuf {
+        runtime_dir().join(format!("{}.pid", self.endpoint()))
+    }
+
+    pub fn logfile(&self) -> PathBuf {
+        runtime_dir().join(format!("{}.log", self.endpoint()))
+    }
+
+    /// The name of the message queue, or the file name of the socket.  Sockets with the
+    /// same file name in different directories share it.
+    fn endpoint(&self) -> String {
+        match self.ipc {
+            Ipc::Mqueue => self.mqueue[1..].into(),
+            Ipc::Socket => self
+                .socket
+                .file_name()
+                .map_or_else(|| SOCKET.into(), |name| name.to_string_lossy().into()),
+        }
+    }
+
+    fn read(path: &Path) -> Result<Option<Self>, String> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == NotFound => return Ok(None),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+
+        toml::from_str(&content)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    fn apply(&mut self, overrides: &Overrides) -> Result<(), String> {
+        let Overrides {
+            mqueue,
+            max_msg_len,
+            capacity,
+            mode_dir,
+            backend,
+            log_level,
+            segment_cmd,
+            ..
+        } = overrides;
+
+        if let Some(mqueue) = mqueue {
+            self.mqueue = mqueue.clone();
+        }
+        if let Some(max_msg_len) = max_msg_len {
+            self.max_msg_len = *max_msg_len;
+        }
+        if let Some(capacity) = capacity {
+            self.capacity = *capacity;
+        }
+        if let Some(mode_dir) = mode_dir {
+            self.mode_dir = mode_dir.clone();
+        }
+        if let Some(backend) = backend {
+            self.backend = *backend;
+        }
+        if let Some(log_level) = log_level {
+            self.log_level = *log_level;
+        }
+        for SegmentCommand { name, command } in segment_cmd {
+            let invalid =
+                |reason| format!("Invalid value for `--segment-cmd`: {} {}", name, reason);
+            match self.segments.iter_mut().find(|s| &s.name == name) {
+                Some(segment) if segment.mode => return Err(invalid("is a mode segment")),
+                Some(segment) => segment.command = command.clone(),
+                None => return Err(invalid("is not a segment")),
+            }
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let invalid = |key, reason| Err(format!("Invalid value for key `{}`: {}", key, reason));
+
+        for (i, segment) in self.segments.iter().enumerate() {
+            if self.segments[..i].iter().any(|s| s.name == segment.name) {
+                return invalid("segment.name", format!("duplicate name {}", segment.name));
+            } else if segment.mode != segment.command.is_empty() {
+                return invalid(
+                    "segment",
+                    format!("{}: either `command` or `mode` should be set", segment.name),
+                );
+            } else if !segment.color.is_none() && !segment.style.foreground.is_none() {
+                return invalid(
+                    "segment.color",
+                    format!("{}: conflicts with `style.foreground`", segment.name),
+                );
+            } else if let Some(tag) = segment.markup.iter().find(|tag| {
+                *tag != ALL_TAGS && !(tag.len() == 1 && markup::TAGS.contains(tag.as_str()))
+            }) {
+                return invalid(
+                    "segment.markup",
+                    format!("{}: unknown tag {:?}", segment.name, tag),
+                );
+            } else if segment.mode && segment.per_monitor {
+                return invalid(
+                    "segment.per_monitor",
+                    format!("{}: mode segments are always per monitor", segment.name),
+                );
+            } else if let Some(action) = &segment.click {
+                if !self.actions.contains_key(action) {
+                    return invalid("segment.click", format!("unknown action {}", action));
+                }
+            }
+        }
+
+        for (i, monitor) in self.monitors.iter().enumerate() {
+            if monitor.name.is_empty() {
+                return invalid("monitor.name", "should not be empty".into());
+            } else if self.monitors[..i].iter().any(|m| m.name == monitor.name) {
+                return invalid("monitor.name", format!("duplicate name {}", monitor.name));
+            }
+        }
+
+        for (name, action) in &self.actions {
+            if name.is_empty() || name.contains(':') {
+                return invalid("actions", format!("invalid action name {:?}", name));
+            } else if let Action::Cycle(ring) = action {
+                if ring.is_empty() {
+                    return invalid("actions", format!("{}: mode list is empty", name));
+                }
+            }
+        }
+
+        if !self.mqueue.starts_with('/') || self.mqueue[1..].contains('/') {
+            invalid(
+                "mqueue",
+                "name should start with '/' and contain no other '/'".into(),
+            )
+        } else if self.max_msg_len == 0 {
+            invalid("max_msg_len", "should be greater than 0".into())
+        } else if self.capacity == 0 {
+            invalid("capacity", "should be greater than 0".into())
+        } else if self.history_size == 0 {
+            invalid("history_size", "should be greater than 0".into())
+        } else if self.supervisor.delay_ms > self.supervisor.max_delay_ms {
+            invalid(
+                "supervisor.delay_ms",
+                "should not be greater than `max_delay_ms`".into(),
+            )
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Segment {
+    fn command(name: &str, command: &[&str], align: Align) -> Self {
+        Self {
+            name: name.into(),
+            command: command.iter().map(|&c| c.into()).collect(),
+            mode: false,
+            align,
+            color: Color::default(),
+            style: Style::default(),
+            markup: Vec::new(),
+            click: None,
+            restart: Restart::default(),
+            per_monitor: false,
+        }
+    }
+}
+
+/// `$XDG_RUNTIME_DIR`, or a directory of the user in the temporary directory if it is not
+/// set.
+pub fn runtime_dir() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(|| env::temp_dir().join(format!("{}-{}", CONFIG_DIR, getuid())))
+}
+
+/// Creates the runtime directory if it does not exist.  Files in it are trusted, so it
+/// has to be a directory of the user with mode 0700, not a symlink.
+pub fn create_runtime_dir() -> Result<PathBuf, String> {
+    let dir = runtime_dir();
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(&dir)
+        .map_err(|e| format!("{}: {}", dir.display(), e))?;
+    check_runtime_dir(&dir)?;
+    Ok(dir)
+}
+
+pub fn check_runtime_dir(dir: &Path) -> Result<(), String> {
+    let metadata = fs::symlink_metadata(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let reason = if !metadata.is_dir() {
+        "it is not a directory".into()
+    } else if metadata.uid() != getuid().as_raw() {
+        format!("it is owned by uid {}", metadata.uid())
+    } else if metadata.mode() & 0o777 != 0o700 {
+        format!("its mode is {:o} instead of 700", metadata.mode() & 0o777)
+    } else {
+        return Ok(());
+    };
+    Err(format!(
+        "Runtime directory {} is unsafe: {}",
+        dir.display(),
+        reason
+    ))
+}
+
+/// Options to open a file in the runtime directory, which fail if the file is a symlink.
+pub fn no_follow() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    options.custom_flags(OFlag::O_NOFOLLOW.bits());
+    options
+}
+
+fn default_path() -> Option<PathBuf> {
+    let mut path = dirs::config_dir()?;
+    path.push(CONFIG_DIR);
+    path.push(CONFIG_FILE);
+    Some(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(toml: &str) -> Result<Config, String> {
+        let config: Config = toml::from_str(toml).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn parses_valid_config() {
+        let config = parse(
+            r#"
+            capacity = 5
+            [[segment]]
+            name = "clock"
+            command = ["date"]
+            click = "next"
+            [[segment]]
+            name = "mode"
+            mode = true
+            [actions]
+            next = "next"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.capacity, 5);
+        assert_eq!(config.segments.len(), 2);
+        assert_eq!(config.max_msg_len, Config::default().max_msg_len);
+    }
+
+    #[test]
+    fn reports_bad_keys() {
+        let unknown = parse("capacty = 5").unwrap_err();
+        assert!(unknown.contains("unknown field `capacty`"), "{}", unknown);
+
+        let wrong_type = parse("capacity = \"5\"").unwrap_err();
+        assert!(wrong_type.contains("for key `capacity`"), "{}", wrong_type);
+        assert!(wrong_type.contains("line 1"), "{}", wrong_type);
+    }
+
+    #[test]
+    fn reports_missing_file() {
+        let overrides = Overrides::from_iter(&["runel", "--config", "/nonexistent/runel.toml"]);
+        assert_eq!(
+            Config::load(&overrides).unwrap_err(),
+            "Config file \"/nonexistent/runel.toml\" does not exist"
+        );
+    }
+
+    #[test]
+    fn overrides_segment_commands() {
+        let mut config = Config::default();
+        let overrides =
+            Overrides::from_iter(&["runel", "--segment-cmd", "title=xdotool  getwindowname"]);
+        config.apply(&overrides).unwrap();
+        let title = config.segments.iter().find(|s| s.name == "title").unwrap();
+        assert_eq!(title.command, ["xdotool", "getwindowname"]);
+        assert_eq!(overrides.args()[1], "title=xdotool getwindowname");
+
+        let mode = Overrides::from_iter(&["runel", "--segment-cmd", "mode=date"]);
+        assert!(config.apply(&mode).is_err());
+        let unknown = Overrides::from_iter(&["runel", "--segment-cmd", "clock=date"]);
+        assert!(config.apply(&unknown).is_err());
+        assert!("title=".parse::<SegmentCommand>().is_err());
+        assert!("xtitle".parse::<SegmentCommand>().is_err());
+    }
+
+    #[test]
+    fn names_pidfile_after_endpoint() {
+        let mut config = parse("mqueue = \"/other\"\nsocket = \"/tmp/bar.sock\"").unwrap();
+        assert!(config.pidfile().ends_with("other.pid"));
+        config.ipc = Ipc::Socket;
+        assert!(config.pidfile().ends_with("bar.sock.pid"));
+        assert!(config.logfile().ends_with("bar.sock.log"));
+    }
+
+    #[test]
+    fn reports_invalid_values() {
+        let cases = [
+            ("capacity = 0", "capacity"),
+            ("mqueue = \"runel\"", "mqueue"),
+            (
+                "supervisor = { delay_ms = 10, max_delay_ms = 1 }",
+                "supervisor.delay_ms",
+            ),
+            (
+                "[[segment]]\nname = \"a\"\nmode = true\n[[segment]]\nname = \"a\"\nmode = true",
+                "segment.name",
+            ),
+            ("[[segment]]\nname = \"a\"", "segment"),
+            (
+                "[[segment]]\nname = \"a\"\ncommand = [\"x\"]\nmarkup = [\"Z\"]",
+                "segment.markup",
+            ),
+            (
+                "[[segment]]\nname = \"a\"\ncommand = [\"x\"]\nclick = \"nope\"",
+                "segment.click",
+            ),
+            ("[[monitor]]\nname = \"\"", "monitor.name"),
+        ];
+
+        for (toml, key) in &cases {
+            let e = parse(toml).unwrap_err();
+            assert!(
+                e.starts_with(&format!("Invalid value for key `{}`:", key)),
+                "{}: {}",
+                toml,
+                e
+            );
+        }
+    }
+}
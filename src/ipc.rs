@@ -0,0 +1,355 @@
+use {
+    serde::{Deserialize, Serialize},
+    std::{
+        collections::BTreeMap,
+        convert::TryFrom,
+        fmt::{self, Display},
+        io::{self, Read, Write},
+        time::Duration,
+    },
+};
+
+/// Maximum length of a reply sent by the server.
+pub const REPLY_MSG_LEN: usize = 4096;
+/// Messages sent through the socket are prefixed with their length as big-endian `u32`.
+const FRAME_HEADER_LEN: usize = 4;
+
+/// A request is framed as `VERSION tag monitor fields...`.  The monitor is a byte telling
+/// whether it is set, followed by its name.  Strings are UTF-8 prefixed with their length
+/// as big-endian `u16`, lists are prefixed with the number of items the same way.
+const VERSION: u8 = 1;
+
+const MODE: u8 = 1;
+const STATUS: u8 = 2;
+const NEXT: u8 = 3;
+const PREV: u8 = 4;
+const PUSH: u8 = 5;
+const POP: u8 = 6;
+const BACK: u8 = 7;
+const RELOAD: u8 = 8;
+const QUIT: u8 = 9;
+const RESTART: u8 = 10;
+const SEND: u8 = 11;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Switch to the mode run with the arguments
+    Mode(String, Vec<String>),
+    /// Query server status; the reply is sent to the message queue with the given name, or
+    /// through the socket the request is received from
+    Status(String),
+    /// Switch to the next mode in the ring
+    Next,
+    /// Switch to the previous mode in the ring
+    Prev,
+    /// Switch to the mode and return to the active one when the timeout ends or on `Pop`
+    Push(String, Vec<String>, Option<Duration>),
+    /// Return to the mode active before the last `Push`
+    Pop,
+    /// Return to the previously active mode
+    Back,
+    /// Read the config again and restart changed commands
+    Reload,
+    /// Stop the server
+    Quit,
+    /// Stop the server and start it again with the same arguments
+    Restart,
+    /// Write the line to stdin of the active mode
+    Send(String),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    pub monitor: String,
+    pub mode: String,
+    pub pid: u32,
+    pub line: String,
+    pub segments: BTreeMap<String, String>,
+}
+
+/// A reply of the server, sent as JSON through the socket, or to the message queue named
+/// in a status request.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Reply {
+    Done,
+    Status(Status),
+    Error(String),
+}
+
+/// Reads fields of a request.
+struct Decoder<'a>(&'a [u8]);
+
+impl Request {
+    /// Encodes the request to the monitor, or to all monitors if `monitor` is `None`.
+    pub fn encode(&self, monitor: Option<&str>) -> Result<Vec<u8>, String> {
+        let mut buf = vec![VERSION, self.tag()];
+        match monitor {
+            Some(monitor) => {
+                buf.push(1);
+                put_str(&mut buf, monitor)?;
+            }
+            None => buf.push(0),
+        }
+
+        match self {
+            Self::Mode(mode, args) => {
+                put_str(&mut buf, mode)?;
+                put_list(&mut buf, args)?;
+            }
+            Self::Status(reply) => put_str(&mut buf, reply)?,
+            Self::Push(mode, args, timeout) => {
+                put_str(&mut buf, mode)?;
+                put_list(&mut buf, args)?;
+                let secs = timeout.map_or(0, |t| t.as_secs());
+                buf.extend_from_slice(&secs.to_be_bytes());
+            }
+            Self::Send(line) => put_str(&mut buf, line)?,
+            Self::Next | Self::Prev | Self::Pop | Self::Back => (),
+            Self::Reload | Self::Quit | Self::Restart => (),
+        }
+        Ok(buf)
+    }
+
+    /// Decodes a request and the monitor it is sent to.
+    pub fn decode(buf: &[u8]) -> Result<(Option<String>, Self), String> {
+        let mut decoder = Decoder(buf);
+        let version = decoder.byte()?;
+        if version != VERSION {
+            return Err(format!("Unsupported protocol version {}", version));
+        }
+
+        let tag = decoder.byte()?;
+        let monitor = match decoder.byte()? {
+            0 => None,
+            1 => Some(decoder.string()?),
+            flag => return Err(format!("Invalid monitor flag {}", flag)),
+        };
+
+        let request = match tag {
+            MODE => Self::Mode(decoder.string()?, decoder.list()?),
+            STATUS => Self::Status(decoder.string()?),
+            NEXT => Self::Next,
+            PREV => Self::Prev,
+            PUSH => {
+                let (mode, args) = (decoder.string()?, decoder.list()?);
+                let secs = u64::from_be_bytes(decoder.array()?);
+                let timeout = Some(secs).filter(|&secs| secs > 0).map(Duration::from_secs);
+                Self::Push(mode, args, timeout)
+            }
+            POP => Self::Pop,
+            BACK => Self::Back,
+            RELOAD => Self::Reload,
+            QUIT => Self::Quit,
+            RESTART => Self::Restart,
+            SEND => Self::Send(decoder.string()?),
+            tag => return Err(format!("Unknown request {}", tag)),
+        };
+
+        if decoder.0.is_empty() {
+            Ok((monitor, request))
+        } else {
+            Err(format!("{} bytes after the request", decoder.0.len()))
+        }
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            Self::Mode(..) => MODE,
+            Self::Status(_) => STATUS,
+            Self::Next => NEXT,
+            Self::Prev => PREV,
+            Self::Push(..) => PUSH,
+            Self::Pop => POP,
+            Self::Back => BACK,
+            Self::Reload => RELOAD,
+            Self::Quit => QUIT,
+            Self::Restart => RESTART,
+            Self::Send(_) => SEND,
+        }
+    }
+}
+
+fn put_len(buf: &mut Vec<u8>, len: usize) -> Result<(), String> {
+    let len = u16::try_from(len).map_err(|_| format!("Request field is too long: {}", len))?;
+    buf.extend_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) -> Result<(), String> {
+    put_len(buf, s.len())?;
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+fn put_list(buf: &mut Vec<u8>, list: &[String]) -> Result<(), String> {
+    put_len(buf, list.len())?;
+    list.iter().try_for_each(|s| put_str(buf, s))
+}
+
+impl Decoder<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8], String> {
+        if self.0.len() < len {
+            return Err("Request is truncated".into());
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        Ok(u16::from_be_bytes(self.array()?).into())
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.len()?;
+        let bytes = self.bytes(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| "Request has invalid UTF-8".into())
+    }
+
+    fn list(&mut self) -> Result<Vec<String>, String> {
+        (0..self.len()?).map(|_| self.string()).collect()
+    }
+}
+
+/// Writes the message to the socket prefixed with its length.
+pub fn write_frame(w: &mut impl Write, msg: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(msg.len()).map_err(io::Error::other)?;
+    w.write_all(&len.to_be_bytes())?;
+    w.write_all(msg)
+}
+
+/// Reads a message written by `write_frame`.  Messages over `max_len` are rejected.
+pub fn read_frame(r: &mut impl Read, max_len: usize) -> io::Result<Vec<u8>> {
+    let mut header = [0; FRAME_HEADER_LEN];
+    r.read_exact(&mut header)?;
+    let len = check_len(&header, max_len).map_err(io::Error::other)?;
+    let mut msg = vec![0; len];
+    r.read_exact(&mut msg)?;
+    Ok(msg)
+}
+
+/// Takes a complete message written by `write_frame` from the start of `buf`, if it has
+/// been read.
+pub fn split_frame(buf: &mut Vec<u8>, max_len: usize) -> Result<Option<Vec<u8>>, String> {
+    if buf.len() < FRAME_HEADER_LEN {
+        return Ok(None);
+    }
+    let len = check_len(&buf[..FRAME_HEADER_LEN], max_len)?;
+    if buf.len() < FRAME_HEADER_LEN + len {
+        return Ok(None);
+    }
+    let msg = buf[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len].to_vec();
+    buf.drain(..FRAME_HEADER_LEN + len);
+    Ok(Some(msg))
+}
+
+fn check_len(header: &[u8], max_len: usize) -> Result<usize, String> {
+    let mut len = [0; FRAME_HEADER_LEN];
+    len.copy_from_slice(header);
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len {
+        Err(format!(
+            "Message of {} bytes is longer than {}",
+            len, max_len
+        ))
+    } else {
+        Ok(len)
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.monitor.is_empty() {
+            writeln!(f, "monitor: {}", self.monitor)?;
+        }
+        writeln!(f, "mode: {}", self.mode)?;
+        writeln!(f, "pid: {}", self.pid)?;
+        write!(f, "line: {}", self.line)?;
+        for (name, value) in &self.segments {
+            write!(f, "\n{}: {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(monitor: Option<&str>, request: Request) {
+        let buf = request.encode(monitor).unwrap();
+        assert_eq!(
+            Request::decode(&buf).unwrap(),
+            (monitor.map(String::from), request)
+        );
+    }
+
+    #[test]
+    fn decodes_encoded_requests() {
+        let args = vec!["Berlin".to_string(), "two words".into()];
+        round_trip(None, Request::Mode("weather".into(), args.clone()));
+        round_trip(Some("HDMI-1"), Request::Mode("ünïcode".into(), Vec::new()));
+        round_trip(None, Request::Status("/runel-42".into()));
+        round_trip(Some("eDP-1"), Request::Next);
+        round_trip(None, Request::Push("volume".into(), args, None));
+        let timeout = Some(Duration::from_secs(5));
+        round_trip(None, Request::Push("volume".into(), Vec::new(), timeout));
+        round_trip(None, Request::Send("toggle".into()));
+        round_trip(None, Request::Quit);
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        let buf = Request::Mode("default".into(), Vec::new())
+            .encode(None)
+            .unwrap();
+        assert!(Request::decode(&[]).is_err());
+        assert!(Request::decode(&buf[..buf.len() - 1]).is_err());
+        assert!(Request::decode(&[buf.as_slice(), &[0]].concat()).is_err());
+
+        let mut other_version = buf.clone();
+        other_version[0] = VERSION + 1;
+        assert_eq!(
+            Request::decode(&other_version).unwrap_err(),
+            format!("Unsupported protocol version {}", VERSION + 1)
+        );
+
+        let mut invalid_utf8 = Request::Send("line".into()).encode(None).unwrap();
+        *invalid_utf8.last_mut().unwrap() = 0xff;
+        assert_eq!(
+            Request::decode(&invalid_utf8).unwrap_err(),
+            "Request has invalid UTF-8"
+        );
+        assert!(Request::decode(b"default").is_err());
+    }
+
+    #[test]
+    fn splits_frames() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"first").unwrap();
+        write_frame(&mut buf, b"second").unwrap();
+        buf.pop();
+
+        assert_eq!(split_frame(&mut buf, 10).unwrap().unwrap(), b"first");
+        assert_eq!(split_frame(&mut buf, 10).unwrap(), None);
+        buf.push(b'd');
+        assert_eq!(read_frame(&mut buf.as_slice(), 10).unwrap(), b"second");
+        assert!(split_frame(&mut buf, 5).is_err());
+    }
+
+    #[test]
+    fn rejects_too_long_fields() {
+        let line = "x".repeat(usize::from(u16::MAX) + 1);
+        assert!(Request::Send(line).encode(None).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use {
+    nix::fcntl::{fcntl, FcntlArg, OFlag},
+    std::{
+        fs::File,
+        io::{self, ErrorKind, Read},
+        os::fd::{AsRawFd, OwnedFd, RawFd},
+    },
+};
+
+/// Reads lines from a file descriptor without blocking, so that it can be polled.
+pub struct LineReader {
+    file: File,
+    buf: Vec<u8>,
+}
+
+impl LineReader {
+    pub fn new(fd: impl Into<OwnedFd>) -> io::Result<Self> {
+        let fd = fd.into();
+        fcntl(fd.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).map_err(io::Error::other)?;
+        Ok(Self {
+            file: File::from(fd),
+            buf: Vec::new(),
+        })
+    }
+
+    /// Appends the available complete lines to `lines`.  Returns `false` at the end of
+    /// input, after appending the last line even if it has no newline.
+    pub fn read(&mut self, lines: &mut Vec<String>) -> io::Result<bool> {
+        let mut chunk = [0; 4096];
+        let open = loop {
+            match self.file.read(&mut chunk) {
+                Ok(0) => break false,
+                Ok(len) => self.buf.extend_from_slice(&chunk[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break true,
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        };
+
+        while let Some(i) = self.buf.iter().position(|&byte| byte == b'\n') {
+            lines.push(String::from_utf8_lossy(&self.buf[..i]).into_owned());
+            self.buf.drain(..=i);
+        }
+        if !open && !self.buf.is_empty() {
+            lines.push(String::from_utf8_lossy(&self.buf).into_owned());
+            self.buf.clear();
+        }
+        Ok(open)
+    }
+}
+
+impl AsRawFd for LineReader {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, nix::unistd::pipe, std::io::Write, std::os::fd::FromRawFd};
+
+    #[test]
+    fn reads_complete_lines_until_end() {
+        let (read, write) = pipe().unwrap();
+        let mut reader = LineReader::new(unsafe { OwnedFd::from_raw_fd(read) }).unwrap();
+        let mut writer = unsafe { File::from_raw_fd(write) };
+        let mut lines = Vec::new();
+
+        writer.write_all(b"a\nb\npart").unwrap();
+        assert!(reader.read(&mut lines).unwrap());
+        assert_eq!(lines, ["a", "b"]);
+
+        lines.clear();
+        assert!(reader.read(&mut lines).unwrap());
+        assert!(lines.is_empty());
+
+        drop(writer);
+        assert!(!reader.read(&mut lines).unwrap());
+        assert_eq!(lines, ["part"]);
+    }
+}
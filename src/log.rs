@@ -0,0 +1,210 @@
+use {
+    crate::config,
+    serde::Deserialize,
+    std::{
+        fmt,
+        fs::{self, File},
+        io::{self, Write},
+        path::{Path, PathBuf},
+        str::FromStr,
+        sync::Mutex,
+        time::{SystemTime, UNIX_EPOCH},
+    },
+};
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Error, format_args!($($arg)*)) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Warn, format_args!($($arg)*)) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Info, format_args!($($arg)*)) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::log($crate::log::Level::Debug, format_args!($($arg)*)) };
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+/// A log file which is rotated when it grows over `max_size` bytes: `file` becomes
+/// `file.1`, `file.1` becomes `file.2` and so on, up to `files` rotated files.
+pub struct Rotation {
+    pub path: PathBuf,
+    pub max_size: u64,
+    pub files: usize,
+}
+
+struct Logger {
+    level: Level,
+    output: Output,
+}
+
+enum Output {
+    Stderr,
+    File {
+        rotation: Rotation,
+        file: File,
+        size: u64,
+    },
+}
+
+/// Until `init` is called, messages of the default level go to stderr.
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    level: Level::Info,
+    output: Output::Stderr,
+});
+
+/// Sets the level and the output of the log.  Without a `rotation` the log goes to stderr.
+pub fn init(level: Level, rotation: Option<Rotation>) -> io::Result<()> {
+    let output = match rotation {
+        Some(rotation) => {
+            let file = open(&rotation.path)?;
+            let size = file.metadata()?.len();
+            Output::File {
+                rotation,
+                file,
+                size,
+            }
+        }
+        None => Output::Stderr,
+    };
+
+    *LOGGER.lock().unwrap() = Logger { level, output };
+    Ok(())
+}
+
+pub fn log(level: Level, args: fmt::Arguments) {
+    let mut logger = LOGGER.lock().unwrap();
+    if level > logger.level {
+        return;
+    }
+
+    let line = format!("{} {:5} {}\n", timestamp(), level, args);
+    // There is nowhere to report failures of the log itself
+    let _ = match &mut logger.output {
+        Output::Stderr => io::stderr().write_all(line.as_bytes()),
+        Output::File {
+            rotation,
+            file,
+            size,
+        } => {
+            if *size > 0 && *size + line.len() as u64 > rotation.max_size {
+                if let Ok(new_file) = rotate(rotation) {
+                    *file = new_file;
+                    *size = 0;
+                }
+            }
+            *size += line.len() as u64;
+            file.write_all(line.as_bytes())
+        }
+    };
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    config::no_follow().create(true).append(true).open(path)
+}
+
+fn rotate(rotation: &Rotation) -> io::Result<File> {
+    let rotated = |i: usize| {
+        let mut path = rotation.path.clone().into_os_string();
+        path.push(format!(".{}", i));
+        PathBuf::from(path)
+    };
+
+    if rotation.files == 0 {
+        fs::remove_file(&rotation.path)?;
+    } else {
+        for i in (1..rotation.files).rev() {
+            let _ = fs::rename(rotated(i), rotated(i + 1));
+        }
+        fs::rename(&rotation.path, rotated(1))?;
+    }
+    open(&rotation.path)
+}
+
+/// UTC time in RFC 3339 format with milliseconds.
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    format_time(now.as_secs(), now.subsec_millis())
+}
+
+/// Formats seconds since the epoch like `timestamp`.
+fn format_time(secs: u64, millis: u32) -> String {
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        millis
+    )
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let level = match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+        };
+        f.pad(level)
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(Self::Error),
+            "warn" => Ok(Self::Warn),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(format!("Unknown log level: {}", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_known_dates() {
+        assert_eq!(format_time(0, 0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_time(951_782_400, 5), "2000-02-29T00:00:00.005Z");
+        assert_eq!(format_time(978_307_199, 999), "2000-12-31T23:59:59.999Z");
+        assert_eq!(format_time(4_107_542_400, 0), "2100-03-01T00:00:00.000Z");
+        assert_eq!(format_time(1_709_210_096, 120), "2024-02-29T12:34:56.120Z");
+    }
+}
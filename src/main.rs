@@ -1,48 +1,239 @@
+#[macro_use]
+mod log;
+
+mod backend;
 mod client;
 mod color;
 mod config;
+mod ipc;
+mod lines;
+mod markup;
 mod mode;
+mod monitor;
 mod server;
+mod signals;
 
-use {color::Color, mode::Mode, structopt::StructOpt};
+use {
+    backend::BackendKind,
+    color::Color,
+    config::{Config, Overrides},
+    ipc::Request,
+    mode::Mode,
+    std::time::Duration,
+    structopt::{clap::ArgGroup, StructOpt},
+};
 
 #[derive(StructOpt)]
+#[structopt(group = ArgGroup::with_name("COMMAND").conflicts_with_all(&["server", "MODE"]))]
 /// A Multi-status wrapper for lemonbar
 struct Args {
-    #[structopt(long = "color-title", name = "COLOR_TITLE", default_value = "")]
-    /// A color for window title
-    title: Color,
+    #[structopt(flatten)]
+    overrides: Overrides,
+    #[structopt(long = "color-title", name = "COLOR_TITLE")]
+    /// A color for window title (overrides `color` of "title" segment)
+    title: Option<Color>,
+    #[structopt(long = "background-title", name = "BACKGROUND_TITLE")]
+    /// A background color for window title
+    title_background: Option<Color>,
+    #[structopt(long = "underline-title", name = "UNDERLINE_TITLE")]
+    /// Underline window title with the color
+    title_underline: Option<Color>,
     #[structopt(short, long, name = "MODE")]
     /// Mode to run
-    mode: Mode,
+    mode: Option<String>,
     #[structopt(short, long)]
     /// Start runel server
     server: bool,
-    #[structopt(name = "LEMONBAR_ARGS", env, last = true, use_delimiter = true)]
-    /// Lemonbar command line arguments
-    lemonbar_args: Vec<String>,
+    #[structopt(long, requires = "server")]
+    /// Do not detach the server from the terminal
+    foreground: bool,
+    #[structopt(
+        long,
+        name = "MONITOR",
+        conflicts_with_all = &["server", "reload", "quit", "restart"]
+    )]
+    /// Send the request to the bar of the monitor only (default: all monitors)
+    monitor: Option<String>,
+    #[structopt(long, group = "COMMAND")]
+    /// Print status of running server
+    status: bool,
+    #[structopt(long, requires = "status")]
+    /// Print status in JSON
+    json: bool,
+    #[structopt(long = "list-modes", group = "COMMAND")]
+    /// List available modes and mark the active one
+    list_modes: bool,
+    #[structopt(long = "next-mode", group = "COMMAND")]
+    /// Switch to the next mode in the ring
+    next_mode: bool,
+    #[structopt(long = "prev-mode", group = "COMMAND")]
+    /// Switch to the previous mode in the ring
+    prev_mode: bool,
+    #[structopt(long = "push-mode", name = "PUSH_MODE", group = "COMMAND")]
+    /// Switch to the mode temporarily, until --pop-mode or timeout
+    push_mode: Option<String>,
+    #[structopt(long, name = "SECONDS", requires = "PUSH_MODE")]
+    /// Return from pushed mode after timeout
+    timeout: Option<u64>,
+    #[structopt(long = "pop-mode", group = "COMMAND")]
+    /// Return to the mode active before the last --push-mode
+    pop_mode: bool,
+    #[structopt(long, group = "COMMAND")]
+    /// Return to the previously active mode
+    back: bool,
+    #[structopt(long, name = "LINE", group = "COMMAND")]
+    /// Write the line to stdin of the active mode
+    send: Option<String>,
+    #[structopt(long, group = "COMMAND")]
+    /// Read config again and restart changed commands of running server
+    reload: bool,
+    #[structopt(long, group = "COMMAND")]
+    /// Stop running server
+    quit: bool,
+    #[structopt(long, group = "COMMAND")]
+    /// Restart running server with the same arguments
+    restart: bool,
+    #[structopt(long, conflicts_with = "server")]
+    /// Fail if the server is not running or its message queue is full
+    strict: bool,
+    #[structopt(long, conflicts_with = "server")]
+    /// Wait until the server is running and takes the request
+    wait: bool,
+    #[structopt(long, conflicts_with_all = &["server", "quit"])]
+    /// Start the server if it is not running, with the mode or the first mode of `modes`
+    autostart: bool,
+    #[structopt(name = "ARGS", last = true)]
+    /// Arguments of the mode, or with --server bar command line arguments (override
+    /// `lemonbar_args` or `dzen2_args` from config)
+    args: Vec<String>,
 }
 
 fn main() {
     let opts = Args::from_args();
 
-    let result = match opts {
+    if let Err(e) = run(opts) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(mut opts: Args) -> Result<(), Box<dyn std::error::Error>> {
+    if opts.server {
+        opts.overrides.make_absolute()?;
+    }
+    let config = Config::load(&opts.overrides)?;
+    let monitor = opts.monitor.clone();
+    let monitor = monitor.as_deref();
+    let delivery = &client::Delivery {
+        strict: opts.strict,
+        wait: opts.wait,
+        autostart: Some(opts.overrides.args()).filter(|_| opts.autostart),
+    };
+
+    match opts {
         Args {
             server: true,
-            mode,
+            mode: Some(mode),
             title,
-            lemonbar_args,
-        } => server::run(lemonbar_args, mode, title),
+            title_background,
+            title_underline,
+            foreground,
+            args: bar_args,
+            overrides,
+            ..
+        } => {
+            let mode = Mode::new(&mode, &config.mode_dir)?;
+            let apply_args = |mut config: Config| {
+                if !bar_args.is_empty() {
+                    match config.backend {
+                        BackendKind::Dzen2 => config.dzen2_args = bar_args.clone(),
+                        _ => config.lemonbar_args = bar_args.clone(),
+                    }
+                }
+                for segment in config.segments.iter_mut().filter(|s| s.name == "title") {
+                    let style = &mut segment.style;
+                    if let Some(color) = title {
+                        style.foreground = color;
+                    }
+                    if let Some(color) = title_background {
+                        style.background = color;
+                    }
+                    if let Some(color) = title_underline {
+                        style.line = color;
+                        style.underline = true;
+                    }
+                }
+                config
+            };
+            let reload = || Config::load(&overrides).map(apply_args);
+            server::run(apply_args(config), mode, foreground, reload)
+        }
+
+        Args {
+            status: true, json, ..
+        } => client::status(&config, monitor, json, delivery),
+
+        Args {
+            list_modes: true, ..
+        } => client::list_modes(&config, monitor, delivery),
+
+        Args {
+            next_mode: true, ..
+        } => client::send(&config, monitor, Request::Next, delivery).map_err(|e| e.into()),
+
+        Args {
+            prev_mode: true, ..
+        } => client::send(&config, monitor, Request::Prev, delivery).map_err(|e| e.into()),
+
+        Args {
+            push_mode: Some(mode),
+            timeout,
+            args,
+            ..
+        } => {
+            let mode = Mode::new(&mode, &config.mode_dir)?;
+            let timeout = timeout.map(Duration::from_secs);
+            let request = Request::Push(mode.mode, args, timeout);
+            client::send(&config, monitor, request, delivery).map_err(|e| e.into())
+        }
+
+        Args { pop_mode: true, .. } => {
+            client::send(&config, monitor, Request::Pop, delivery).map_err(|e| e.into())
+        }
+
+        Args { back: true, .. } => {
+            client::send(&config, monitor, Request::Back, delivery).map_err(|e| e.into())
+        }
+
+        Args {
+            send: Some(line), ..
+        } => client::send(&config, monitor, Request::Send(line), delivery).map_err(|e| e.into()),
+
+        Args { reload: true, .. } => {
+            client::send(&config, None, Request::Reload, delivery).map_err(|e| e.into())
+        }
+
+        Args { quit: true, .. } => {
+            client::send(&config, None, Request::Quit, delivery).map_err(|e| e.into())
+        }
+
+        Args { restart: true, .. } => {
+            client::send(&config, None, Request::Restart, delivery).map_err(|e| e.into())
+        }
 
         Args {
             server: false,
-            mode,
+            mode: Some(mode),
+            args,
             ..
-        } => client::run(mode).map_err(|e| e.into()),
-    };
+        } => {
+            let mode = Mode {
+                args,
+                ..Mode::new(&mode, &config.mode_dir)?
+            };
+            client::run(&config, monitor, mode, delivery).map_err(|e| e.into())
+        }
 
-    if let Err(e) = result {
-        eprintln!("{}", e);
-        std::process::exit(1);
+        Args { mode: None, .. } => Err("Invalid arguments: mode is not specified".into()),
     }
 }
@@ -0,0 +1,211 @@
+/// Names of lemonbar tags a trusted segment may be allowed to emit.  `u` and `o` stand for
+/// `+u`, `-u`, `!u` and their overline counterparts.
+pub const TAGS: &str = "ABFUTORSlcruo";
+/// Allows every tag.
+pub const ALL_TAGS: &str = "*";
+
+/// Escapes `%` in lemonbar text, except for formatting blocks made only of `allowed` tags.
+/// With no allowed tags the whole text is displayed as is.
+pub fn escape_lemonbar(text: &str, allowed: &[String]) -> String {
+    let is_allowed = |tag: char| {
+        allowed
+            .iter()
+            .any(|a| a == ALL_TAGS || a.chars().eq(Some(tag)))
+    };
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(i) = rest.find('%') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        let len = match block(rest) {
+            Some((len, tags)) if !allowed.is_empty() && tags.iter().all(|&tag| is_allowed(tag)) => {
+                len
+            }
+            _ if !allowed.is_empty() && rest.starts_with("%%") => 2,
+            _ => {
+                out.push('%');
+                1
+            }
+        };
+        out.push_str(&rest[..len]);
+        rest = &rest[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Escapes dzen2 commands unless the segment is trusted.
+pub fn escape_dzen2(text: &str, allowed: &[String]) -> String {
+    if allowed.is_empty() {
+        text.replace('^', "^^")
+    } else {
+        text.into()
+    }
+}
+
+/// Parses a formatting block at the start of `text` the way lemonbar does.  Returns its
+/// length and the names of its tags, or `None` if lemonbar would read it differently.
+fn block(text: &str) -> Option<(usize, Vec<char>)> {
+    let body = text.strip_prefix("%{")?;
+    // Lemonbar ends the block at the first `}`, even inside a command.
+    let body = &body[..body.find('}')?];
+    let bytes = body.as_bytes();
+    let count =
+        |from: usize, pred: fn(&u8) -> bool| bytes[from..].iter().take_while(|&b| pred(b)).count();
+    let mut tags = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let tag = bytes[i];
+        i += 1;
+        match tag {
+            b' ' => {}
+            b'A' => {
+                tags.push('A');
+                if matches!(bytes.get(i), Some(b'1'..=b'5')) {
+                    i += 1;
+                }
+                if bytes.get(i) == Some(&b':') {
+                    i += command(&body[i + 1..])? + 2;
+                }
+            }
+            b'+' | b'-' | b'!' => {
+                tags.push(*bytes.get(i)? as char);
+                i += 1;
+            }
+            b'R' | b'l' | b'c' | b'r' => tags.push(tag as char),
+            b'B' | b'F' | b'U' => {
+                tags.push(tag as char);
+                i += match bytes.get(i)? {
+                    b'-' => 1,
+                    b'#' => 1 + count(i + 1, u8::is_ascii_hexdigit),
+                    _ => return None,
+                };
+            }
+            b'T' => {
+                tags.push('T');
+                i += match bytes.get(i)? {
+                    b'-' => 1,
+                    _ => Some(count(i, u8::is_ascii_digit)).filter(|&n| n > 0)?,
+                };
+            }
+            b'O' => {
+                tags.push('O');
+                let sign = matches!(bytes.get(i), Some(b'+' | b'-')) as usize;
+                i += sign + Some(count(i + sign, u8::is_ascii_digit)).filter(|&n| n > 0)?;
+            }
+            b'S' => {
+                tags.push('S');
+                match bytes.get(i)? {
+                    b'+' | b'-' | b'f' | b'l' | b'0'..=b'9' => i += 1,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some((body.len() + 3, tags))
+}
+
+/// Returns the length of a non-empty clickable area command, which ends at the first
+/// unescaped `:`.
+fn command(text: &str) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let len = (0..bytes.len()).find(|&i| bytes[i] == b':' && (i == 0 || bytes[i - 1] != b'\\'))?;
+    Some(len).filter(|&len| len > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allow(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|&t| t.into()).collect()
+    }
+
+    #[test]
+    fn escapes_untrusted_text() {
+        assert_eq!(
+            escape_lemonbar("%{A:rm -rf ~:}title%{A} 100%", &[]),
+            "%%{A:rm -rf ~:}title%%{A} 100%%"
+        );
+    }
+
+    #[test]
+    fn keeps_allowed_tags() {
+        let allowed = allow(&["F", "B", "u"]);
+        assert_eq!(
+            escape_lemonbar("%{F#fff B-}a%{+u}b%{-u}%{F-}", &allowed),
+            "%{F#fff B-}a%{+u}b%{-u}%{F-}"
+        );
+        assert_eq!(escape_lemonbar("50%% %{F#fff}", &allowed), "50%% %{F#fff}");
+    }
+
+    #[test]
+    fn escapes_blocks_with_forbidden_tags() {
+        let allowed = allow(&["F"]);
+        assert_eq!(
+            escape_lemonbar("%{F#fff A:reboot:}x%{A}", &allowed),
+            "%%{F#fff A:reboot:}x%%{A}"
+        );
+        assert_eq!(escape_lemonbar("%{F#fff", &allowed), "%%{F#fff");
+    }
+
+    #[test]
+    fn keeps_clickable_areas_with_spaces() {
+        let allowed = allow(&[ALL_TAGS]);
+        assert_eq!(
+            escape_lemonbar("%{A3:notify-send hi:}x%{A}", &allowed),
+            "%{A3:notify-send hi:}x%{A}"
+        );
+    }
+
+    #[test]
+    fn escapes_tags_chained_after_arguments() {
+        assert_eq!(
+            escape_lemonbar("%{F-A:poweroff:}click%{F-A}", &allow(&["F"])),
+            "%%{F-A:poweroff:}click%%{F-A}"
+        );
+        assert_eq!(
+            escape_lemonbar("%{B-A3:menu:}", &allow(&["B"])),
+            "%%{B-A3:menu:}"
+        );
+        assert_eq!(
+            escape_lemonbar("%{T1A:menu:}", &allow(&["T"])),
+            "%%{T1A:menu:}"
+        );
+        assert_eq!(
+            escape_lemonbar("%{O-10A:menu:}", &allow(&["O"])),
+            "%%{O-10A:menu:}"
+        );
+        assert_eq!(
+            escape_lemonbar("%{S+A:menu:}", &allow(&["S"])),
+            "%%{S+A:menu:}"
+        );
+        assert_eq!(
+            escape_lemonbar("%{U#f00A:menu:}", &allow(&["U"])),
+            "%%{U#f00A:menu:}"
+        );
+    }
+
+    #[test]
+    fn parses_tag_arguments_like_lemonbar() {
+        let allowed = allow(&["T", "O", "S", "U"]);
+        assert_eq!(
+            escape_lemonbar("%{T2 O-10 S1 U#ff0000}x%{T- Sf}", &allowed),
+            "%{T2 O-10 S1 U#ff0000}x%{T- Sf}"
+        );
+        assert_eq!(
+            escape_lemonbar("%{Ured}%{Tx}%{O}", &allowed),
+            "%%{Ured}%%{Tx}%%{O}"
+        );
+        let allowed = allow(&["A"]);
+        assert_eq!(
+            escape_lemonbar("%{A:a\\:b:}x%{A}", &allowed),
+            "%{A:a\\:b:}x%{A}"
+        );
+        assert_eq!(escape_lemonbar("%{A:a}b:}", &allowed), "%%{A:a}b:}");
+    }
+}
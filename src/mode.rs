@@ -1,44 +1,111 @@
-use {
-    crate::config::CONFIG_DIR,
-    std::{
-        fmt::{self, Display},
-        fs::DirBuilder,
-        path::PathBuf,
-        str::FromStr,
-    },
+use std::{
+    fmt::{self, Display},
+    fs::{self, DirBuilder, File},
+    io::{self, BufRead, BufReader},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
 };
 
-#[derive(Debug, PartialEq, Eq)]
+/// Prefix of a comment line with mode description.
+const DESCRIPTION_PREFIX: &str = "# runel:";
+/// Number of lines at the beginning of a mode where the description is looked for.
+const DESCRIPTION_LINES: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Mode {
     pub mode: String,
     pub path: PathBuf,
+    /// Command line arguments of the mode
+    pub args: Vec<String>,
 }
 
-impl FromStr for Mode {
-    type Err = String;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut path = dirs::config_dir().unwrap();
-        path.push(CONFIG_DIR);
+impl Mode {
+    pub fn new(mode: &str, dir: &Path) -> Result<Self, String> {
         DirBuilder::new()
             .recursive(true)
-            .create(&path)
+            .create(dir)
             .map_err(|e| format!("{}", e))?;
-        path.push(s);
+        let path = dir.join(mode);
 
         if !(path.is_file() && path.exists()) {
-            Err(format!("Mode \"{}\" does not exists", s))
+            Err(format!("Mode \"{}\" does not exists", mode))
         } else {
             Ok(Self {
-                mode: s.into(),
+                mode: mode.into(),
                 path,
+                args: Vec::new(),
             })
         }
     }
 }
 
+pub struct ModeInfo {
+    pub mode: String,
+    pub description: Option<String>,
+}
+
+/// Lists executable files of `dir` sorted by name.
+pub fn list(dir: &Path) -> io::Result<Vec<ModeInfo>> {
+    let mut modes = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // Dangling symlinks and unreadable entries are not modes
+        let metadata = match fs::metadata(entry.path()) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 {
+            modes.push(ModeInfo {
+                mode: entry.file_name().to_string_lossy().into(),
+                description: description(&entry.path()),
+            });
+        }
+    }
+
+    modes.sort_by(|a, b| a.mode.cmp(&b.mode));
+    Ok(modes)
+}
+
+/// Finds a `# runel: description` line in the header of a mode.
+fn description(path: &Path) -> Option<String> {
+    let file = BufReader::new(File::open(path).ok()?);
+
+    file.split(b'\n')
+        .take(DESCRIPTION_LINES)
+        .map_while(Result::ok)
+        .find_map(|line| {
+            let line = String::from_utf8(line).ok()?;
+            let description = line.strip_prefix(DESCRIPTION_PREFIX)?.trim();
+            Some(description.to_string()).filter(|d| !d.is_empty())
+        })
+}
+
 impl Display for Mode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", &self.mode)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::os::unix::fs::symlink};
+
+    #[test]
+    fn skips_dangling_symlinks() {
+        let dir = std::env::temp_dir().join(format!("runel-modes-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mode = dir.join("clock");
+        fs::write(&mode, "#!/bin/sh\n# runel: Current time\ndate\n").unwrap();
+        fs::set_permissions(&mode, fs::Permissions::from_mode(0o755)).unwrap();
+        symlink("/nonexistent", dir.join("broken")).unwrap();
+
+        let modes = list(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let modes = modes.unwrap();
+        assert_eq!(modes.len(), 1);
+        assert_eq!(modes[0].mode, "clock");
+        assert_eq!(modes[0].description.as_deref(), Some("Current time"));
+    }
+}
@@ -0,0 +1,112 @@
+use {
+    serde::Deserialize,
+    std::{io, process::Command},
+};
+
+/// An output with its own bar.  The empty name stands for the single bar of a setup
+/// without configured monitors.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Monitor {
+    pub name: String,
+    #[serde(default)]
+    pub geometry: Option<String>,
+}
+
+/// Lists monitors reported by `xrandr --listmonitors`.
+pub fn detect() -> io::Result<Vec<Monitor>> {
+    let output = Command::new("xrandr").arg("--listmonitors").output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("xrandr {}", output.status)));
+    }
+
+    let monitors = parse_xrandr(&String::from_utf8_lossy(&output.stdout));
+    if monitors.is_empty() {
+        Err(io::Error::other("xrandr reported no monitors"))
+    } else {
+        Ok(monitors)
+    }
+}
+
+/// Parses output of `xrandr --listmonitors`:
+///
+/// ```text
+/// Monitors: 2
+///  0: +*DP-1 1920/527x1080/296+0+0  DP-1
+///  1: +HDMI-1 1280/338x1024/270+1920+0  HDMI-1
+/// ```
+///
+/// The bar of a monitor takes its width and position; the height is left to lemonbar.
+pub fn parse_xrandr(output: &str) -> Vec<Monitor> {
+    output
+        .lines()
+        .skip(1)
+        .filter_map(parse_xrandr_line)
+        .collect()
+}
+
+fn parse_xrandr_line(line: &str) -> Option<Monitor> {
+    let mut fields = line.split_whitespace();
+    fields.next().filter(|index| index.ends_with(':'))?;
+    let name = fields.next()?.trim_start_matches(['+', '*']);
+    let (size, position) = fields.next()?.split_once('+')?;
+
+    let width: u32 = size.split(['/', 'x']).next()?.parse().ok()?;
+    let (x, y) = position.split_once('+')?;
+    let (x, y): (u32, u32) = (x.parse().ok()?, y.parse().ok()?);
+
+    Some(Monitor {
+        name: name.into(),
+        geometry: Some(format!("{}x+{}+{}", width, x, y)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(name: &str, geometry: &str) -> Monitor {
+        Monitor {
+            name: name.into(),
+            geometry: Some(geometry.into()),
+        }
+    }
+
+    #[test]
+    fn parses_xrandr_monitors() {
+        let output = "Monitors: 2\n \
+                      0: +*DP-1 1920/527x1080/296+0+0  DP-1\n \
+                      1: +HDMI-1 1280/338x1024/270+1920+56  HDMI-1\n";
+
+        assert_eq!(
+            parse_xrandr(output),
+            vec![
+                monitor("DP-1", "1920x+0+0"),
+                monitor("HDMI-1", "1280x+1920+56"),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_malformed_lines() {
+        let output = "Monitors: 3\n \
+                      0: +*eDP-1 2560/344x1440/194+0+0  eDP-1\n \
+                      garbage\n \
+                      2: +VIRTUAL 800x600+a+0  none\n";
+
+        assert_eq!(parse_xrandr(output), vec![monitor("eDP-1", "2560x+0+0")]);
+    }
+
+    #[test]
+    fn parses_monitors_without_physical_size() {
+        let output = "Monitors: 1\n 0: VIRTUAL 800x600+0+0  none\n";
+
+        assert_eq!(parse_xrandr(output), vec![monitor("VIRTUAL", "800x+0+0")]);
+    }
+
+    #[test]
+    fn parses_empty_output() {
+        assert_eq!(parse_xrandr(""), vec![]);
+        assert_eq!(parse_xrandr("Monitors: 0\n"), vec![]);
+    }
+}
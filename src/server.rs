@@ -1,106 +1,891 @@
 use {
     crate::{
-        config::{CAPACITY, CONFIG_DIR, MAX_MSG_LEN, MQUEUE, TITLE_CMD, WMSTATUS_CMD},
-        mode::Mode,
-        Color,
+        backend::{self, Backend, BackendKind, Clicks, I3BAR_CONTINUED},
+        config::{self, Action, Config, Ipc, Restart, Segment, Supervisor, CONFIG_DIR},
+        ipc::{self, Reply, Request, Status},
+        lines::LineReader,
+        log::{self, Rotation},
+        mode::{self, Mode},
+        monitor::{self, Monitor},
+        signals::{self, unblocked},
     },
     daemonize::Daemonize,
     nix::{
-        sys::signal::{kill, Signal::SIGTERM},
+        errno::Errno,
+        fcntl::{fcntl, flock, FcntlArg, FlockArg, OFlag},
+        libc::PIPE_BUF,
+        poll::{poll, PollFd, PollFlags},
+        sys::{
+            signal::{kill, Signal},
+            signalfd::SignalFd,
+        },
         unistd::Pid,
     },
-    posixmq::{unlink, OpenOptions},
-    simple_signal::Signal,
+    posixmq::{unlink, OpenOptions, PosixMq},
     std::{
-        collections::HashMap,
+        collections::{HashMap, VecDeque},
+        convert::TryFrom,
+        env,
         error::Error,
-        fmt::Write as FmtWrite,
-        fs::File,
-        io::{self, BufRead, BufReader, Write},
-        path::PathBuf,
-        process::{Child, ChildStdout, Command, Stdio},
-        sync::{mpsc, Arc, RwLock},
-        thread,
+        fs::{self, File},
+        io::{
+            self,
+            ErrorKind::{Interrupted, WouldBlock},
+            Read, Write,
+        },
+        mem,
+        os::{
+            fd::{AsFd, AsRawFd, RawFd},
+            unix::{
+                net::{UnixListener, UnixStream},
+                process::CommandExt,
+            },
+        },
+        path::{Path, PathBuf},
+        process::{self, Child, ChildStdin, Command, ExitStatus, Stdio},
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
     },
 };
 
-type Sender = mpsc::Sender<Result<(), Arc<io::Error>>>;
 type Res<T> = io::Result<T>;
-type Value = Arc<RwLock<String>>;
-type CmdOut = BufReader<ChildStdout>;
+type Reload<'a> = &'a dyn Fn() -> Result<Config, String>;
 
-enum Message {
-    Ok,
+/// How long children are given to exit when the server stops.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long a terminated child is given to exit before it is killed.
+const KILL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// What to do when the event loop ends.
+#[derive(Clone, Copy)]
+enum Exit {
     Quit,
+    Restart,
 }
 
-enum KillerMessage {
-    Child(usize, Child),
-    Kill(usize),
-    Signal,
+/// A file descriptor polled by the event loop.
+enum Source {
+    Signals,
+    Mqueue,
+    Listener,
+    /// Client connected to the socket with the id
+    Connection(usize),
+    /// Stdout of the supervised command with the id
+    Command(usize),
+    /// Stdout of the active mode of the bar with the index
+    Mode(usize),
+    /// Clicks on the bar with the index
+    Clicks(usize),
 }
 
-enum Update {
-    Id(usize),
-    Mode(Mode),
+/// Children asked to exit with SIGTERM.  They are killed if they do not exit in time.
+#[derive(Default)]
+struct Exiting(Vec<(Child, Instant)>);
+
+/// A client connected to the socket, with the part of its request read so far.
+struct Connection {
+    id: usize,
+    stream: UnixStream,
+    buf: Vec<u8>,
 }
 
-#[derive(Default)]
-struct Bar {
-    wmstatus: Value,
-    title: Value,
-    mode: Value,
+/// A running command.  Its stdout is `None` after the end of output, until it exits.
+struct Process {
+    child: Child,
+    stdout: Option<LineReader>,
 }
 
-pub fn run(lemonbar_args: Vec<String>, mode: Mode, title: Color) -> Result<(), Box<dyn Error>> {
-    start_daemon()?;
+/// A segment command restarted according to the restart policy.  While the command is
+/// down, its value is `down_marker` and `restart_at` is the time to restart it.
+struct Supervised {
+    id: usize,
+    name: String,
+    command: Vec<String>,
+    restart: Restart,
+    value: String,
+    process: Option<Process>,
+    started: Instant,
+    restarts: usize,
+    delay: Duration,
+    restart_at: Option<Instant>,
+}
+
+/// Commands of a segment: one shared by all bars, or one per bar if `per_monitor` is set.
+struct SegmentCommands {
+    command: Vec<String>,
+    restart: Restart,
+    per_monitor: bool,
+    supervisors: Vec<Supervised>,
+}
 
-    let mut out = lemonbar_out(lemonbar_args)?;
-    let mut buf = String::new();
-    let (tx, rx) = mpsc::channel();
-    let bar = Bar::default();
+struct RunningMode {
+    mode: Mode,
+    id: usize,
+    pid: u32,
+    /// `None` after the mode closed its stdout
+    child: Option<Child>,
+    stdout: Option<LineReader>,
+    stdin: Option<ChildStdin>,
+}
 
-    let wmstatus_child = start_command(Arc::clone(&bar.wmstatus), WMSTATUS_CMD, tx.clone())?;
-    let title_child = start_command(Arc::clone(&bar.title), TITLE_CMD, tx.clone())?;
-    let killer_tx = start_child_killer(vec![wmstatus_child, title_child])?;
-    start_listener(Arc::clone(&bar.mode), tx, mode, killer_tx)?;
+/// A mode started by `Request::Push`.  `id` is the id of its process.
+struct Pushed {
+    id: usize,
+    previous: Mode,
+}
+
+struct Modes {
+    current: RunningMode,
+    mode_dir: PathBuf,
+    /// Environment of mode processes
+    env: Vec<(&'static str, String)>,
+    ring: Vec<String>,
+    history: VecDeque<Mode>,
+    history_size: usize,
+    stack: Vec<Pushed>,
+    /// Ends of push timeouts with ids of the pushed modes
+    timeouts: Vec<(Instant, usize)>,
+    /// Previously active modes
+    exiting: Exiting,
+}
+
+/// The bar of a monitor with its own modes.
+struct Screen {
+    monitor: String,
+    value: String,
+    modes: Modes,
+}
+
+/// The bar of a monitor.  A bar program which exited is started again after a delay,
+/// which doubles on quick exits like the one of supervised commands.
+struct Panel {
+    monitor: Monitor,
+    /// `None` until the bar is started again
+    bar: Option<Box<dyn Backend>>,
+    clicks: Option<Clicks>,
+    /// Last line written to the bar
+    frame: String,
+    started: Instant,
+    delay: Duration,
+    respawn_at: Option<Instant>,
+}
+
+struct Server {
+    config: Config,
+    monitors: Vec<String>,
+    panels: Vec<Panel>,
+    screens: Vec<Screen>,
+    commands: HashMap<String, SegmentCommands>,
+    signals: SignalFd,
+    mq: Option<PosixMq>,
+    mq_buffer: Vec<u8>,
+    listener: Option<UnixListener>,
+    connections: Vec<Connection>,
+    /// Stopped commands
+    exiting: Exiting,
+    /// Commands of shell actions
+    shells: Vec<Child>,
+    changed: bool,
+    /// Earliest time of the next render
+    render_at: Instant,
+    /// Set when the server stops, with the time to give up waiting for children
+    exit: Option<(Exit, Instant)>,
+    /// The fatal error the server stopped on, returned once children exited
+    error: Option<Box<dyn Error>>,
+}
 
-    for msg in rx {
-        msg.map_err(|e| Arc::try_unwrap(e).unwrap())?;
-        print_bar(&title, &mut buf, &bar)?;
-        write!(out, "{}", &buf)?;
-        out.flush()?;
-        buf.clear();
+/// Runs the server.  It stays in the foreground if `foreground` is set or if its stdin is
+/// used by the backend.  On SIGHUP or `Request::Reload` the config is read again with
+/// `reload`.
+pub fn run(
+    config: Config,
+    mode: Mode,
+    foreground: bool,
+    reload: impl Fn() -> Result<Config, String>,
+) -> Result<(), Box<dyn Error>> {
+    // The daemon runs in `/`, relative arguments are resolved in this directory on restart
+    let cwd = env::current_dir()?;
+    let runtime_dir = config::create_runtime_dir()?;
+    let mut pidfile = lock(&config.pidfile())?;
+
+    match config.backend {
+        _ if foreground => (),
+        BackendKind::I3bar => (),
+        backend => start_daemon(&runtime_dir, backend == BackendKind::Stdout)?,
+    }
+    pidfile.set_len(0)?;
+    writeln!(pidfile, "{}", process::id())?;
+
+    let rotation = if foreground {
+        None
+    } else {
+        Some(Rotation {
+            path: config.logfile(),
+            max_size: config.log_max_size,
+            files: config.log_files,
+        })
+    };
+    log::init(config.log_level, rotation)?;
+    info!("Server {} started", process::id());
+    backend::begin(&config)?;
+
+    let monitors = monitors(&config);
+    let mut server = Server {
+        monitors: monitors.iter().map(|m| m.name.clone()).collect(),
+        panels: Vec::new(),
+        screens: Vec::new(),
+        commands: HashMap::new(),
+        signals: signals::block()?,
+        mq: None,
+        mq_buffer: vec![0; config.max_msg_len],
+        listener: None,
+        connections: Vec::new(),
+        exiting: Exiting::default(),
+        shells: Vec::new(),
+        changed: false,
+        render_at: Instant::now(),
+        exit: None,
+        error: None,
+        config,
+    };
+    server.start_commands();
+    let started = server.start(monitors, mode);
+    server.fail(started);
+
+    match server.run(&reload)? {
+        Exit::Quit => Ok(()),
+        Exit::Restart => restart(&cwd, server.config.backend),
     }
-    Ok(())
 }
 
-fn lemonbar_out(args: Vec<String>) -> Res<impl Write> {
-    let mut child = Command::new("lemonbar")
-        .args(&args)
-        .stdin(Stdio::piped())
-        .spawn()?;
+fn listen(config: &Config) -> Res<PosixMq> {
+    let _ = unlink(&config.mqueue);
 
-    let stdin = child
-        .stdin
-        .take()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No stdout of process"))?;
+    OpenOptions::readonly()
+        .nonblocking()
+        .max_msg_len(config.max_msg_len)
+        .capacity(config.capacity)
+        .create_new()
+        .open(&config.mqueue)
+}
 
-    Ok(stdin)
+/// Listens on the socket.  A socket left by a previous server is removed, the pidfile lock
+/// ensures that it is not running.
+fn bind(socket: &Path) -> Res<UnixListener> {
+    let _ = fs::remove_file(socket);
+    let listener = UnixListener::bind(socket)?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
 }
 
-fn print_bar(title: &Color, out: &mut String, bar: &Bar) -> std::fmt::Result {
-    writeln!(
-        out,
-        "%{{l}} {} {}%{{r}} {} ",
-        bar.wmstatus.read().unwrap(),
-        title.draw(bar.title.read().unwrap()),
-        bar.mode.read().unwrap()
-    )
+/// Replaces the process with a new server started with the same arguments in the working
+/// directory `cwd`.  The pidfile lock is released as its descriptor is closed on exec.  With
+/// i3bar, the new server continues the stream on the same stdout.
+fn restart(cwd: &Path, backend: BackendKind) -> Result<(), Box<dyn Error>> {
+    info!("Restarting server");
+    let mut command = Command::new(env::current_exe()?);
+    if backend == BackendKind::I3bar {
+        command.env(I3BAR_CONTINUED, "1");
+    }
+    let e = unblocked(&mut command)
+        .args(env::args_os().skip(1))
+        .current_dir(cwd)
+        .exec();
+    Err(e.into())
+}
+
+/// Monitors detected with xrandr, if enabled, or configured ones.  Without monitors a
+/// single bar is run.
+fn monitors(config: &Config) -> Vec<Monitor> {
+    if !config.backend.has_monitors() {
+        return vec![Monitor::default()];
+    } else if config.detect_monitors {
+        match monitor::detect() {
+            Ok(monitors) => return monitors,
+            Err(e) => warn!("Detecting monitors: {}", e),
+        }
+    }
+
+    if config.monitors.is_empty() {
+        vec![Monitor::default()]
+    } else {
+        config.monitors.clone()
+    }
 }
 
-fn update_value(value: &Value, new_value: &str) -> bool {
-    let mut value = value.write().unwrap();
+impl Server {
+    /// Starts the bars and modes on the monitors, then listens to requests.
+    fn start(&mut self, monitors: Vec<Monitor>, mode: Mode) -> Result<(), Box<dyn Error>> {
+        for monitor in monitors {
+            let (bar, clicks) = backend::start(&self.config, &monitor)?;
+            self.screens.push(Screen {
+                monitor: monitor.name.clone(),
+                value: String::new(),
+                modes: Modes::new(&self.config, &monitor.name, mode.clone())?,
+            });
+            self.panels.push(Panel {
+                monitor,
+                bar: Some(bar),
+                clicks,
+                frame: String::new(),
+                started: Instant::now(),
+                delay: Duration::from_millis(self.config.supervisor.delay_ms),
+                respawn_at: None,
+            });
+        }
+        match self.config.ipc {
+            Ipc::Mqueue => self.mq = Some(listen(&self.config)?),
+            Ipc::Socket => self.listener = Some(bind(&self.config.socket)?),
+        }
+        Ok(())
+    }
+
+    /// Polls children, the message queue or socket and signals until the server stops.
+    /// Fatal errors stop the server too and are returned once children exited.
+    fn run(&mut self, reload: Reload) -> Result<Exit, Box<dyn Error>> {
+        loop {
+            let sources = self.sources();
+            let mut fds: Vec<PollFd> = sources
+                .iter()
+                .map(|&(fd, _)| PollFd::new(fd, PollFlags::POLLIN))
+                .collect();
+            match poll(&mut fds, self.timeout(Instant::now())) {
+                Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => (),
+                Err(e) => self.fail(Err(e.into())),
+            }
+
+            for ((_, source), fd) in sources.into_iter().zip(fds) {
+                if fd.revents().is_some_and(|events| !events.is_empty()) {
+                    let handled = self.handle(source, reload);
+                    self.fail(handled);
+                }
+            }
+            self.tick(Instant::now());
+
+            if let Some((exit, deadline)) = self.exit {
+                let exited = self.exiting.is_empty()
+                    && self.screens.iter().all(|s| s.modes.exiting.is_empty());
+                if exited || Instant::now() >= deadline {
+                    if !exited {
+                        warn!("Children did not exit in {:?}", SHUTDOWN_TIMEOUT);
+                    }
+                    for bar in self.panels.drain(..).filter_map(|panel| panel.bar) {
+                        bar.close()?;
+                    }
+                    return self.error.take().map_or(Ok(exit), Err);
+                }
+            } else if self.changed && Instant::now() >= self.render_at {
+                let rendered = self.render();
+                self.fail(rendered);
+            }
+        }
+    }
+
+    fn sources(&self) -> Vec<(RawFd, Source)> {
+        let mut sources = vec![(self.signals.as_raw_fd(), Source::Signals)];
+        if let Some(mq) = &self.mq {
+            sources.push((mq.as_raw_fd(), Source::Mqueue));
+        }
+        if let Some(listener) = &self.listener {
+            sources.push((listener.as_raw_fd(), Source::Listener));
+        }
+        for connection in &self.connections {
+            sources.push((
+                connection.stream.as_raw_fd(),
+                Source::Connection(connection.id),
+            ));
+        }
+        for supervised in self.commands.values().flat_map(|c| &c.supervisors) {
+            if let Some(stdout) = supervised.process.as_ref().and_then(|p| p.stdout.as_ref()) {
+                sources.push((stdout.as_raw_fd(), Source::Command(supervised.id)));
+            }
+        }
+        for (i, screen) in self.screens.iter().enumerate() {
+            if let Some(stdout) = &screen.modes.current.stdout {
+                sources.push((stdout.as_raw_fd(), Source::Mode(i)));
+            }
+        }
+        for (i, panel) in self.panels.iter().enumerate() {
+            if let Some(clicks) = &panel.clicks {
+                sources.push((clicks.input.as_raw_fd(), Source::Clicks(i)));
+            }
+        }
+        sources
+    }
+
+    /// Milliseconds until the nearest timer, or -1 without timers.
+    fn timeout(&self, now: Instant) -> i32 {
+        let restarts = self
+            .commands
+            .values()
+            .flat_map(|c| &c.supervisors)
+            .filter_map(|s| s.restart_at);
+        let modes = self.screens.iter().filter_map(|s| s.modes.deadline());
+        let bars = self.panels.iter().filter_map(|p| p.respawn_at);
+        let deadline = restarts
+            .chain(modes)
+            .chain(bars)
+            .chain(self.exiting.deadline())
+            .chain(Some(self.render_at).filter(|_| self.changed))
+            .chain(self.exit.map(|(_, deadline)| deadline))
+            .min();
+
+        match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(now);
+                left.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+            }
+            None => -1,
+        }
+    }
+
+    fn handle(&mut self, source: Source, reload: Reload) -> Result<(), Box<dyn Error>> {
+        match source {
+            Source::Signals => {
+                while let Some(info) = self.signals.read_signal()? {
+                    match Signal::try_from(info.ssi_signo as i32)? {
+                        Signal::SIGHUP => self.reload(reload),
+                        Signal::SIGCHLD => self.reap(),
+                        _ => {
+                            info!("Terminating on signal");
+                            self.stop(Exit::Quit);
+                        }
+                    }
+                }
+            }
+            Source::Mqueue => self.receive(reload)?,
+            Source::Listener => self.accept(),
+            Source::Connection(id) => self.serve(id, reload),
+            Source::Command(id) => {
+                let supervisor = &self.config.supervisor;
+                let mut supervised = self.commands.values_mut().flat_map(|c| &mut c.supervisors);
+                if let Some(supervised) = supervised.find(|s| s.id == id) {
+                    self.changed |= supervised.read(supervisor);
+                }
+            }
+            Source::Mode(bar) => {
+                let screen = &mut self.screens[bar];
+                if let Some(line) = screen.modes.read() {
+                    self.changed |= update_value(&mut screen.value, &line);
+                }
+            }
+            Source::Clicks(bar) => self.click(bar),
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, reload: Reload) -> Result<(), Box<dyn Error>> {
+        while let Some(mq) = &self.mq {
+            let len = match mq.receive(&mut self.mq_buffer) {
+                Ok((_, len)) => len,
+                Err(e) if e.kind() == WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            let (monitor, request) = match Request::decode(&self.mq_buffer[..len]) {
+                Ok(request) => request,
+                Err(e) => {
+                    error!("Invalid request: {}", e);
+                    continue;
+                }
+            };
+
+            let reply_to = match &request {
+                Request::Status(reply) => Some(reply.clone()),
+                _ => None,
+            };
+            let reply = self.request(monitor, request, reload);
+            if let Some(reply_to) = reply_to {
+                if let Err(e) = send_reply(&reply_to, &reply) {
+                    error!("Request: {}", e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self) {
+        while let Some(listener) = &self.listener {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == WouldBlock => break,
+                Err(e) => {
+                    error!("Accepting connection: {}", e);
+                    break;
+                }
+            };
+            if let Err(e) = stream.set_nonblocking(true) {
+                error!("Accepting connection: {}", e);
+                continue;
+            }
+            self.connections.push(Connection {
+                id: next_id(),
+                stream,
+                buf: Vec::new(),
+            });
+        }
+    }
+
+    /// Reads from the connection with the id.  When a request is complete, the reply is
+    /// written and the connection is closed.
+    fn serve(&mut self, id: usize, reload: Reload) {
+        let i = match self.connections.iter().position(|c| c.id == id) {
+            Some(i) => i,
+            None => return,
+        };
+        let connection = &mut self.connections[i];
+        let mut chunk = [0; 1024];
+        match connection.stream.read(&mut chunk) {
+            Ok(0) => {
+                self.connections.swap_remove(i);
+                return;
+            }
+            Ok(len) => connection.buf.extend_from_slice(&chunk[..len]),
+            Err(e) if e.kind() == WouldBlock || e.kind() == Interrupted => return,
+            Err(e) => {
+                debug!("Reading request: {}", e);
+                self.connections.swap_remove(i);
+                return;
+            }
+        }
+
+        let decoded = match ipc::split_frame(&mut connection.buf, self.config.max_msg_len) {
+            Ok(None) => return,
+            Ok(Some(request)) => Request::decode(&request),
+            Err(e) => Err(e),
+        };
+        let mut connection = self.connections.swap_remove(i);
+        let reply = match decoded {
+            Ok((monitor, request)) => self.request(monitor, request, reload),
+            Err(e) => {
+                error!("Invalid request: {}", e);
+                Reply::Error(e)
+            }
+        };
+
+        // The reply is smaller than the socket buffer, so writing it does not block
+        let written = serde_json::to_vec(&reply)
+            .map_err(io::Error::other)
+            .and_then(|reply| ipc::write_frame(&mut connection.stream, &reply));
+        if let Err(e) = written {
+            error!("Replying to request: {}", e);
+        }
+    }
+
+    /// Handles the request and returns the reply for the client.
+    fn request(&mut self, monitor: Option<String>, request: Request, reload: Reload) -> Reply {
+        info!("Request {:?} to monitor {:?}", request, monitor);
+        match request {
+            Request::Reload => self.reload(reload),
+            Request::Quit => self.stop(Exit::Quit),
+            Request::Restart => self.stop(Exit::Restart),
+            request => match self.handle_request(monitor.as_deref(), request) {
+                Ok(Some(status)) => return Reply::Status(status),
+                Ok(None) => (),
+                Err(e) => {
+                    error!("Request: {}", e);
+                    return Reply::Error(e.to_string());
+                }
+            },
+        }
+        Reply::Done
+    }
+
+    /// Handles the request on the monitor, or on all monitors if `monitor` is `None`.
+    /// Status of the first of them is returned.
+    fn handle_request(
+        &mut self,
+        monitor: Option<&str>,
+        request: Request,
+    ) -> Result<Option<Status>, Box<dyn Error>> {
+        let targets: Vec<usize> = (0..self.screens.len())
+            .filter(|&i| monitor.is_none_or(|monitor| monitor == self.screens[i].monitor))
+            .collect();
+        if targets.is_empty() {
+            return Err(format!("Unknown monitor \"{}\"", monitor.unwrap_or_default()).into());
+        }
+
+        for bar in targets {
+            let modes = &mut self.screens[bar].modes;
+            match request.clone() {
+                Request::Mode(mode, args) => modes.switch(modes.find(&mode, args)?)?,
+                Request::Next => modes.step(1)?,
+                Request::Prev => modes.step(-1)?,
+                Request::Push(mode, args, timeout) => {
+                    modes.push(modes.find(&mode, args)?, timeout)?
+                }
+                Request::Pop => modes.pop()?,
+                Request::Back => modes.back()?,
+                Request::Send(line) => modes.send(&line)?,
+                // Handled by the server
+                Request::Reload | Request::Quit | Request::Restart => (),
+                Request::Status(_) => return Ok(Some(self.status(bar))),
+            }
+        }
+        Ok(None)
+    }
+
+    fn click(&mut self, bar: usize) {
+        let clicks = match &mut self.panels[bar].clicks {
+            Some(clicks) => clicks,
+            None => return,
+        };
+        let mut lines = Vec::new();
+        let open = clicks.input.read(&mut lines).unwrap_or_else(|e| {
+            error!("Reading clicks of bar {}: {}", bar, e);
+            false
+        });
+        let actions: Vec<String> = lines.iter().filter_map(|l| clicks.action(l)).collect();
+        if !open {
+            warn!("Bar {} closed its output", bar);
+            self.panels[bar].clicks = None;
+        }
+
+        for action in actions {
+            debug!("Click {} on bar {}", action, bar);
+            let modes = &mut self.screens[bar].modes;
+            if let Err(e) = dispatch_click(&action, &self.config.actions, modes, &mut self.shells) {
+                error!("Click {}: {}", action, e);
+            }
+        }
+    }
+
+    /// Reaps children which exited.
+    fn reap(&mut self) {
+        let now = Instant::now();
+        let supervisor = &self.config.supervisor;
+        for supervised in self.commands.values_mut().flat_map(|c| &mut c.supervisors) {
+            self.changed |= supervised.try_wait(supervisor);
+        }
+        for screen in &mut self.screens {
+            screen.modes.exiting.reap(now);
+        }
+        self.exiting.reap(now);
+        self.shells
+            .retain_mut(|child| matches!(child.try_wait(), Ok(None)));
+
+        for bar in 0..self.panels.len() {
+            let status = match &mut self.panels[bar].bar {
+                Some(backend) => backend.try_wait(),
+                None => continue,
+            };
+            match status {
+                Ok(None) => (),
+                Ok(Some(status)) => {
+                    warn!("Bar {} exited: {}", bar, status);
+                    self.bar_failed(bar);
+                }
+                Err(e) => error!("Waiting for bar {}: {}", bar, e),
+            }
+        }
+    }
+
+    /// Closes the bar and schedules to start it again.
+    fn bar_failed(&mut self, bar: usize) {
+        let min_delay = Duration::from_millis(self.config.supervisor.delay_ms);
+        let max_delay = Duration::from_millis(self.config.supervisor.max_delay_ms);
+        let panel = &mut self.panels[bar];
+
+        if let Some(backend) = panel.bar.take() {
+            if let Err(e) = backend.close() {
+                error!("Closing bar {}: {}", bar, e);
+            }
+        }
+        panel.clicks = None;
+        panel.frame.clear();
+        if self.exit.is_some() {
+            return;
+        }
+
+        if panel.started.elapsed() >= max_delay {
+            panel.delay = min_delay;
+        }
+        info!("Restarting bar {} in {:?}", bar, panel.delay);
+        panel.respawn_at = Some(Instant::now() + panel.delay);
+        panel.delay = (panel.delay * 2).min(max_delay);
+    }
+
+    /// Starts the bar again and renders the last line on it.
+    fn respawn(&mut self, bar: usize) {
+        let panel = &mut self.panels[bar];
+        panel.respawn_at = None;
+
+        match backend::start(&self.config, &panel.monitor) {
+            Ok((backend, clicks)) => {
+                info!("Bar {} started", bar);
+                panel.bar = Some(backend);
+                panel.clicks = clicks;
+                panel.started = Instant::now();
+                self.changed = true;
+            }
+            Err(e) => {
+                error!("Starting bar {}: {}", bar, e);
+                self.bar_failed(bar);
+            }
+        }
+    }
+
+    /// Runs timers which are due.
+    fn tick(&mut self, now: Instant) {
+        let supervisor = &self.config.supervisor;
+        for supervised in self.commands.values_mut().flat_map(|c| &mut c.supervisors) {
+            if supervised.restart_at.is_some_and(|at| at <= now) {
+                self.changed |= supervised.start(supervisor);
+            }
+        }
+        for screen in &mut self.screens {
+            if let Err(e) = screen.modes.tick(now) {
+                error!("Timeout: {}", e);
+            }
+        }
+        for bar in 0..self.panels.len() {
+            if self.panels[bar].respawn_at.is_some_and(|at| at <= now) {
+                self.respawn(bar);
+            }
+        }
+        self.exiting.reap(now);
+    }
+
+    /// Writes the line to the bars whose line changed since the last render.  Bar
+    /// programs which fail to read it are started again.
+    fn render(&mut self) -> Result<(), Box<dyn Error>> {
+        self.changed = false;
+        self.render_at = Instant::now() + Duration::from_millis(self.config.render_interval_ms);
+
+        let mut buf = String::new();
+        for bar in 0..self.panels.len() {
+            let values: Vec<String> = self
+                .config
+                .segments
+                .iter()
+                .map(|segment| self.value(segment, bar).to_string())
+                .collect();
+            let panel = &mut self.panels[bar];
+            let backend = match &mut panel.bar {
+                Some(backend) => backend,
+                None => continue,
+            };
+
+            backend.format(&self.config.segments, &values, &mut buf)?;
+            if buf != panel.frame {
+                match backend.write(&buf) {
+                    Ok(()) => mem::swap(&mut buf, &mut panel.frame),
+                    Err(e) if self.config.backend.is_program() => {
+                        error!("Writing to bar {}: {}", bar, e);
+                        self.bar_failed(bar);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            buf.clear();
+        }
+        Ok(())
+    }
+
+    /// The value of the segment on the bar.
+    fn value(&self, segment: &Segment, bar: usize) -> &str {
+        match self.commands.get(&segment.name) {
+            Some(commands) if commands.per_monitor => &commands.supervisors[bar].value,
+            Some(commands) => &commands.supervisors[0].value,
+            None => &self.screens[bar].value,
+        }
+    }
+
+    fn status(&self, bar: usize) -> Status {
+        let screen = &self.screens[bar];
+        Status {
+            monitor: screen.monitor.clone(),
+            mode: screen.modes.current.mode.mode.clone(),
+            pid: screen.modes.current.pid,
+            line: screen.value.clone(),
+            segments: self
+                .config
+                .segments
+                .iter()
+                .filter(|segment| !segment.mode)
+                .map(|segment| (segment.name.clone(), self.value(segment, bar).into()))
+                .collect(),
+        }
+    }
+
+    /// Starts commands of the segments.  Commands already running for a segment with the
+    /// same command are kept, the others are stopped.
+    fn start_commands(&mut self) {
+        let mut running = mem::take(&mut self.commands);
+
+        for segment in self.config.segments.iter().filter(|segment| !segment.mode) {
+            let commands = match running.remove(&segment.name) {
+                Some(old) if old.runs(segment) => old,
+                old => {
+                    if let Some(mut old) = old {
+                        old.stop(&mut self.exiting);
+                    }
+                    SegmentCommands::start(segment, &self.monitors, &self.config.supervisor)
+                }
+            };
+            self.commands.insert(segment.name.clone(), commands);
+        }
+
+        for old in running.values_mut() {
+            old.stop(&mut self.exiting);
+        }
+    }
+
+    /// Reads the config again.  Only segments, supervisor settings, actions, the render
+    /// interval and the mode ring are applied.
+    fn reload(&mut self, reload: Reload) {
+        match reload() {
+            Ok(new) => {
+                info!("Config reloaded");
+                for screen in &mut self.screens {
+                    screen.modes.env = mode_env(&new, &screen.monitor);
+                    screen.modes.ring = new.modes.clone();
+                }
+                self.config.segments = new.segments;
+                self.config.supervisor = new.supervisor;
+                self.config.actions = new.actions;
+                self.config.render_interval_ms = new.render_interval_ms;
+                self.start_commands();
+                self.changed = true;
+            }
+            Err(e) => error!("Reloading config: {}", e),
+        }
+    }
+
+    /// Stops the server on a fatal error.  Only the first error is kept.
+    fn fail(&mut self, result: Result<(), Box<dyn Error>>) {
+        if let Err(e) = result {
+            self.error.get_or_insert(e);
+            self.stop(Exit::Quit);
+        }
+    }
+
+    /// Stops listening to requests and terminates all children.  The event loop ends when
+    /// they exited.
+    fn stop(&mut self, exit: Exit) {
+        if self.exit.is_some() {
+            return;
+        }
+
+        info!("Shutting down");
+        if self.mq.take().is_some() {
+            let _ = unlink(&self.config.mqueue);
+        }
+        if self.listener.take().is_some() {
+            let _ = fs::remove_file(&self.config.socket);
+        }
+        self.connections.clear();
+        for commands in self.commands.values_mut() {
+            commands.stop(&mut self.exiting);
+        }
+        for screen in &mut self.screens {
+            screen.modes.stop();
+        }
+        self.exit = Some((exit, Instant::now() + SHUTDOWN_TIMEOUT));
+    }
+}
+
+fn update_value(value: &mut String, new_value: &str) -> bool {
     let is_new = *value != new_value;
 
     if is_new {
@@ -111,234 +896,545 @@ fn update_value(value: &Value, new_value: &str) -> bool {
     is_new
 }
 
-fn command_stdout(command: &[&str]) -> Res<(Child, CmdOut)> {
-    let mut child = Command::new(command[0])
-        .args(&command[1..])
+fn spawn(command: &mut Command, stdin: Stdio) -> Res<(Child, LineReader)> {
+    let mut child = unblocked(command)
+        .stdin(stdin)
         .stdout(Stdio::piped())
         .spawn()?;
 
     let stdout = child
         .stdout
         .take()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "No stdout of process"))?;
+        .ok_or_else(|| io::Error::other("No stdout of process"))?;
 
-    Ok((child, BufReader::new(stdout)))
+    Ok((child, LineReader::new(stdout)?))
 }
 
-fn start_command(value: Value, command: &[&str], tx: Sender) -> Res<Child> {
-    let mut new_buf = String::new();
-    let (child, mut stdout) = command_stdout(command)?;
+fn next_id() -> usize {
+    static LAST_ID: AtomicUsize = AtomicUsize::new(0);
+    LAST_ID.fetch_add(1, Ordering::Relaxed)
+}
 
-    thread::spawn(move || loop {
-        match stdout.read_line(&mut new_buf) {
-            Ok(0) => break,
-            Ok(_) => {
-                new_buf.pop();
-                let mut buf = value.write().unwrap();
-                if new_buf != *buf {
-                    std::mem::swap(&mut *buf, &mut new_buf);
-                    tx.send(Ok(())).unwrap();
-                }
-                new_buf.clear();
+impl SegmentCommands {
+    /// Starts the command of the segment once, or once per monitor with `{monitor}`
+    /// replaced by the monitor name.
+    fn start(segment: &Segment, monitors: &[String], supervisor: &Supervisor) -> Self {
+        let monitors = if segment.per_monitor {
+            monitors.iter().map(String::as_str).collect()
+        } else {
+            vec![""]
+        };
+
+        let supervisors = monitors
+            .into_iter()
+            .map(|monitor| {
+                let mut supervised = Supervised {
+                    id: next_id(),
+                    name: segment.name.clone(),
+                    command: segment
+                        .command
+                        .iter()
+                        .map(|arg| arg.replace("{monitor}", monitor))
+                        .collect(),
+                    restart: segment.restart,
+                    value: String::new(),
+                    process: None,
+                    started: Instant::now(),
+                    restarts: 0,
+                    delay: Duration::from_millis(supervisor.delay_ms),
+                    restart_at: None,
+                };
+                supervised.start(supervisor);
+                supervised
+            })
+            .collect();
+
+        Self {
+            command: segment.command.clone(),
+            restart: segment.restart,
+            per_monitor: segment.per_monitor,
+            supervisors,
+        }
+    }
+
+    /// Whether the commands run as the segment wants them to.
+    fn runs(&self, segment: &Segment) -> bool {
+        self.command == segment.command
+            && self.restart == segment.restart
+            && self.per_monitor == segment.per_monitor
+    }
+
+    fn stop(&mut self, exiting: &mut Exiting) {
+        info!("Stopping command {:?}", self.command);
+        for supervised in &mut self.supervisors {
+            supervised.restart_at = None;
+            if let Some(process) = supervised.process.take() {
+                exiting.terminate(process.child);
+            }
+        }
+    }
+}
+
+impl Supervised {
+    /// Starts the command.  Returns whether the value changed.
+    fn start(&mut self, supervisor: &Supervisor) -> bool {
+        self.restart_at = None;
+        self.started = Instant::now();
+
+        let mut command = Command::new(&self.command[0]);
+        command.args(&self.command[1..]);
+        // Stdin of the server carries clicks with the i3bar backend
+        match spawn(&mut command, Stdio::null()) {
+            Ok((child, stdout)) => {
+                info!("Command {:?} started (pid {})", self.command, child.id());
+                self.process = Some(Process {
+                    child,
+                    stdout: Some(stdout),
+                });
+                false
+            }
+            Err(e) => self.exited(Err(e), supervisor),
+        }
+    }
+
+    /// Reads output of the command; its last line is the value.  Returns whether the value
+    /// changed.
+    fn read(&mut self, supervisor: &Supervisor) -> bool {
+        let stdout = match self.process.as_mut().and_then(|p| p.stdout.as_mut()) {
+            Some(stdout) => stdout,
+            None => return false,
+        };
+        let mut lines = Vec::new();
+        let open = stdout.read(&mut lines).unwrap_or_else(|e| {
+            error!("Command {} failed: {}", self.name, e);
+            false
+        });
+
+        let changed = match lines.last() {
+            Some(line) => update_value(&mut self.value, line),
+            None => false,
+        };
+        if open {
+            changed
+        } else {
+            if let Some(process) = &mut self.process {
+                process.stdout = None;
+            }
+            self.try_wait(supervisor) || changed
+        }
+    }
+
+    /// Handles the exit of the command after the end of its output.  Returns whether the
+    /// value changed.
+    fn try_wait(&mut self, supervisor: &Supervisor) -> bool {
+        let process = match &mut self.process {
+            Some(process) if process.stdout.is_none() => process,
+            _ => return false,
+        };
+        let status = match process.child.try_wait() {
+            Ok(None) => return false,
+            Ok(Some(status)) => Ok(status),
+            Err(e) => Err(e),
+        };
+        self.process = None;
+        self.exited(status, supervisor)
+    }
+
+    /// Shows the down marker and schedules a restart according to the restart policy.
+    /// Returns whether the value changed.
+    fn exited(&mut self, status: Res<ExitStatus>, supervisor: &Supervisor) -> bool {
+        let min_delay = Duration::from_millis(supervisor.delay_ms);
+        let max_delay = Duration::from_millis(supervisor.max_delay_ms);
+        let success = match &status {
+            Ok(status) => {
+                warn!("Command {} exited: {}", self.name, status);
+                status.success()
             }
             Err(e) => {
-                tx.send(Err(Arc::new(e))).unwrap();
-                break;
+                error!("Command {} failed: {}", self.name, e);
+                false
             }
+        };
+
+        if self.started.elapsed() >= max_delay {
+            self.restarts = 0;
+            self.delay = min_delay;
+        }
+
+        let restart = match self.restart {
+            Restart::Always => true,
+            Restart::OnFailure => !success,
+            Restart::Never => false,
+        };
+        if !restart {
+        } else if self.restarts >= supervisor.max_restarts {
+            error!("Command {} restarted too many times", self.name);
+        } else {
+            info!("Restarting command {} in {:?}", self.name, self.delay);
+            self.restart_at = Some(Instant::now() + self.delay);
+            self.delay = (self.delay * 2).min(max_delay);
+            self.restarts += 1;
         }
-    });
-    Ok(child)
+
+        update_value(&mut self.value, &supervisor.down_marker)
+    }
+}
+
+/// Sends the reply to a status request to the message queue of the client.
+fn send_reply(reply_to: &str, reply: &Reply) -> Result<(), Box<dyn Error>> {
+    let mq = OpenOptions::writeonly().nonblocking().open(reply_to)?;
+    mq.send(0, &serde_json::to_vec(reply)?)?;
+    Ok(())
 }
 
-fn make_uid() -> impl FnMut() -> usize {
-    let mut id: usize = 0;
-    move || {
-        id = id.overflowing_add(1).0;
-        id
+fn dispatch_click(
+    action: &str,
+    actions: &HashMap<String, Action>,
+    modes: &mut Modes,
+    shells: &mut Vec<Child>,
+) -> Result<(), Box<dyn Error>> {
+    match actions.get(action) {
+        Some(Action::Mode(mode)) => modes.switch_to(mode)?,
+        Some(Action::Cycle(ring)) => modes.cycle(ring, 1)?,
+        Some(Action::Next) => modes.step(1)?,
+        Some(Action::Prev) => modes.step(-1)?,
+        Some(Action::Send(line)) => modes.send(line)?,
+        Some(Action::Shell(command)) => {
+            shells.push(
+                // Stdin and stdout of the server may be the bar protocol
+                unblocked(&mut Command::new("sh"))
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .spawn()?,
+            );
+        }
+        None => modes.send(action)?,
     }
+    Ok(())
 }
 
-fn start_listener(
-    value: Value,
-    tx: Sender,
-    Mode { mut mode, path }: Mode,
-    killer_tx: mpsc::Sender<KillerMessage>,
-) -> Res<()> {
-    if let Err(_) = unlink(MQUEUE) {}
+impl Modes {
+    /// Starts the mode on the monitor.
+    fn new(config: &Config, monitor: &str, mode: Mode) -> Res<Self> {
+        let env = mode_env(config, monitor);
+        Ok(Self {
+            current: start_mode(mode, next_id(), &env)?,
+            mode_dir: config.mode_dir.clone(),
+            env,
+            ring: config.modes.clone(),
+            history: VecDeque::with_capacity(config.history_size),
+            history_size: config.history_size,
+            stack: Vec::new(),
+            timeouts: Vec::new(),
+            exiting: Exiting::default(),
+        })
+    }
 
-    let mq = OpenOptions::readonly()
-        .max_msg_len(MAX_MSG_LEN)
-        .capacity(CAPACITY)
-        .create_new()
-        .open(MQUEUE)?;
-    let mut mq_buffer = [0; MAX_MSG_LEN];
-
-    let mut uid = make_uid();
-    let mut id = uid();
-    let mut buffer = Arc::new(RwLock::new(String::new()));
-    let (mut mtx, mrx) = mpsc::channel();
-    let (utx, urx) = mpsc::channel();
-
-    start_mode(
-        path,
-        Arc::clone(&buffer),
-        id,
-        mrx,
-        utx.clone(),
-        killer_tx.clone(),
-    )?;
-    thread::spawn({
-        let (tx, utx) = (tx.clone(), utx.clone());
-        move || loop {
-            match mq.receive(&mut mq_buffer) {
-                Ok((_, len)) => match String::from_utf8_lossy(&mq_buffer[..len]).parse() {
-                    Ok(mode) => utx.send(Ok(Update::Mode(mode))).unwrap(),
-                    _ => (),
-                },
-                Err(e) => {
-                    tx.send(Err(Arc::new(e))).unwrap();
-                    break;
-                }
+    /// The mode run with the arguments.
+    fn find(&self, mode: &str, args: Vec<String>) -> Result<Mode, String> {
+        Ok(Mode {
+            args,
+            ..Mode::new(mode, &self.mode_dir)?
+        })
+    }
+
+    /// Switches to the mode unless it is already active with the same arguments.
+    fn switch(&mut self, mode: Mode) -> Res<()> {
+        if mode != self.current.mode {
+            let running = start_mode(mode, next_id(), &self.env)?;
+            let mut previous = mem::replace(&mut self.current, running);
+            if let Some(child) = previous.child.take() {
+                self.exiting.terminate(child);
             }
+
+            self.history.push_front(previous.mode);
+            self.history.truncate(self.history_size);
         }
-    });
-    thread::spawn(move || {
-        for update in urx {
-            match update {
-                Ok(Update::Mode(Mode { mode: m, path })) if m != mode => {
-                    if let Err(_) = mtx.send(Message::Quit) {}
-                    mode = m;
-                    buffer = Arc::new(RwLock::new(String::new()));
-                    let (mtxn, mrx) = mpsc::channel();
-                    mtx = mtxn;
-                    id = uid();
-                    if let Err(e) = start_mode(
-                        path,
-                        Arc::clone(&buffer),
-                        id,
-                        mrx,
-                        utx.clone(),
-                        killer_tx.clone(),
-                    ) {
-                        tx.send(Err(Arc::new(e))).unwrap();
-                    }
-                }
-                Ok(Update::Id(i)) if id == i => {
-                    if update_value(&value, &buffer.read().unwrap()) {
-                        tx.send(Ok(())).unwrap();
-                    }
-                    mtx.send(Message::Ok).unwrap();
-                }
-                Err(e) => tx.send(Err(e)).unwrap(),
-                Ok(_) => (),
+        Ok(())
+    }
+
+    /// Switches to the mode and remembers the active one to return to it on `pop` or when
+    /// the timeout ends.
+    fn push(&mut self, mode: Mode, timeout: Option<Duration>) -> Res<()> {
+        let previous = self.current.mode.clone();
+        self.switch(mode)?;
+
+        let id = self.current.id;
+        self.stack.push(Pushed { id, previous });
+        if self.stack.len() > self.history_size {
+            self.stack.remove(0);
+        }
+
+        if let Some(timeout) = timeout {
+            self.timeouts.push((Instant::now() + timeout, id));
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Result<(), Box<dyn Error>> {
+        let pushed = self.stack.pop().ok_or("Mode stack is empty")?;
+        Ok(self.switch(pushed.previous)?)
+    }
+
+    /// Pops the pushed mode if its timeout ended.  If another mode was selected since the
+    /// push, the pushed mode is just forgotten.
+    fn expire(&mut self, id: usize) -> Result<(), Box<dyn Error>> {
+        match self.stack.last() {
+            Some(pushed) if pushed.id == id && self.current.id == id => self.pop(),
+            Some(pushed) if pushed.id == id => {
+                self.stack.pop();
+                Ok(())
             }
+            _ => Ok(()),
         }
-    });
+    }
 
-    Ok(())
-}
+    fn back(&mut self) -> Result<(), Box<dyn Error>> {
+        let mode = self.history.pop_front().ok_or("Mode history is empty")?;
+        Ok(self.switch(mode)?)
+    }
 
-fn start_mode(
-    path: PathBuf,
-    buf: Value,
-    id: usize,
-    mode_rx: mpsc::Receiver<Message>,
-    update_tx: mpsc::Sender<Result<Update, Arc<io::Error>>>,
-    killer_tx: mpsc::Sender<KillerMessage>,
-) -> Res<()> {
-    let (child, mut stdout) = command_stdout(&[path.to_str().unwrap()])?;
-    killer_tx.send(KillerMessage::Child(id, child)).unwrap();
-
-    thread::spawn(move || {
-        loop {
-            {
-                let mut b = buf.write().unwrap();
-                b.clear();
-                match stdout.read_line(&mut b) {
-                    Ok(0) => break,
-                    Ok(_) => {
-                        b.pop();
-                    }
-                    Err(e) => {
-                        update_tx.send(Err(Arc::new(e))).unwrap();
-                        break;
-                    }
-                }
+    fn switch_to(&mut self, mode: &str) -> Result<(), Box<dyn Error>> {
+        let mode = self.find(mode, Vec::new())?;
+        Ok(self.switch(mode)?)
+    }
+
+    /// Switches to the mode `offset` positions away from the active one in the ring.  The
+    /// ring is either configured or consists of all available modes sorted by name.
+    fn step(&mut self, offset: isize) -> Result<(), Box<dyn Error>> {
+        let ring = if self.ring.is_empty() {
+            mode::list(&self.mode_dir)?
+                .into_iter()
+                .map(|info| info.mode)
+                .collect()
+        } else {
+            self.ring.clone()
+        };
+        self.cycle(&ring, offset)
+    }
+
+    fn cycle(&mut self, ring: &[String], offset: isize) -> Result<(), Box<dyn Error>> {
+        let len = ring.len() as isize;
+        let next = match ring.iter().position(|m| *m == self.current.mode.mode) {
+            Some(i) => (i as isize + offset).rem_euclid(len),
+            None if offset > 0 => 0,
+            None => len - 1,
+        };
+
+        match ring.get(next as usize) {
+            Some(mode) => self.switch_to(mode),
+            None => Err("No modes to cycle through".into()),
+        }
+    }
+
+    /// Writes the line to stdin of the active mode with a single write, which is atomic up
+    /// to `PIPE_BUF` bytes: the line is written whole or dropped if the pipe is full.
+    fn send(&mut self, line: &str) -> Res<()> {
+        let stdin = match &mut self.current.stdin {
+            Some(stdin) => stdin,
+            None => return Ok(()),
+        };
+        let line = format!("{}\n", line);
+        if line.len() > PIPE_BUF {
+            return Err(io::Error::other(format!(
+                "Line longer than {} bytes",
+                PIPE_BUF - 1
+            )));
+        }
+        match stdin.write(line.as_bytes()) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == WouldBlock => {
+                warn!(
+                    "Mode {} does not read its stdin, line dropped",
+                    self.current.mode
+                );
+                Ok(())
             }
-            update_tx.send(Ok(Update::Id(id))).unwrap();
-            match mode_rx.recv().unwrap() {
-                Message::Ok => (),
-                Message::Quit => break,
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads output of the active mode.  Returns its last line, if any.
+    fn read(&mut self) -> Option<String> {
+        let current = &mut self.current;
+        let mut lines = Vec::new();
+        let open = current
+            .stdout
+            .as_mut()?
+            .read(&mut lines)
+            .unwrap_or_else(|e| {
+                error!("Mode {} failed: {}", current.mode, e);
+                false
+            });
+
+        if !open {
+            info!(
+                "Mode {} (pid {}) closed its stdout",
+                current.mode, current.pid
+            );
+            current.stdout = None;
+            if let Some(child) = current.child.take() {
+                self.exiting.terminate(child);
             }
         }
-        killer_tx.send(KillerMessage::Kill(id)).unwrap();
-    });
-    Ok(())
+        lines.pop()
+    }
+
+    /// Returns from pushed modes whose timeouts ended and reaps previous modes.
+    fn tick(&mut self, now: Instant) -> Result<(), Box<dyn Error>> {
+        self.exiting.reap(now);
+        let (ended, timeouts) = self.timeouts.iter().partition(|&&(end, _)| end <= now);
+        self.timeouts = timeouts;
+        for (_, id) in ended {
+            self.expire(id)?;
+        }
+        Ok(())
+    }
+
+    /// The nearest time something has to be done.
+    fn deadline(&self) -> Option<Instant> {
+        let timeouts = self.timeouts.iter().map(|&(end, _)| end);
+        timeouts.chain(self.exiting.deadline()).min()
+    }
+
+    fn stop(&mut self) {
+        self.timeouts.clear();
+        if let Some(child) = self.current.child.take() {
+            self.current.stdout = None;
+            self.exiting.terminate(child);
+        }
+    }
 }
 
-fn kill_child(mut child: Child) {
-    if let Err(e) = kill(Pid::from_raw(child.id() as i32), SIGTERM) {
-        eprintln!("{}", e);
-    } else {
-        std::thread::sleep(std::time::Duration::from_secs(1));
-        match child.try_wait() {
-            Ok(None) => {
-                if let Err(e) = child.kill() {
-                    eprintln!("{}", e);
-                } else if let Err(e) = child.wait() {
-                    eprintln!("{}", e);
-                }
+/// Environment of modes on the monitor: `RUNEL_MONITOR`, `RUNEL_PID` of the server,
+/// `RUNEL_CONFIG_DIR` and colors of the mode segment, if set.
+fn mode_env(config: &Config, monitor: &str) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("RUNEL_MONITOR", monitor.to_string()),
+        ("RUNEL_PID", process::id().to_string()),
+        (
+            "RUNEL_CONFIG_DIR",
+            config.config_dir.to_string_lossy().into(),
+        ),
+    ];
+
+    if let Some(segment) = config.segments.iter().find(|segment| segment.mode) {
+        let style = &segment.style;
+        for (name, color) in &[
+            ("RUNEL_FOREGROUND", style.foreground),
+            ("RUNEL_BACKGROUND", style.background),
+            ("RUNEL_LINE", style.line),
+        ] {
+            if let Some(color) = color.rgb() {
+                env.push((name, color));
             }
-            Err(e) => eprintln!("{}", e),
-            _ => (),
         }
     }
+    env
 }
 
-fn start_child_killer(mut children: Vec<Child>) -> Res<mpsc::Sender<KillerMessage>> {
-    let (killer_tx, killer_rx) = mpsc::channel();
-    let mut status_children: HashMap<usize, Child> = HashMap::new();
+/// Starts the mode with `RUNEL_MODE` set to its name in addition to `env`.
+fn start_mode(mode: Mode, id: usize, env: &[(&str, String)]) -> Res<RunningMode> {
+    let mut command = Command::new(&mode.path);
+    command
+        .args(&mode.args)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .env("RUNEL_MODE", &mode.mode);
+    let (mut child, stdout) = spawn(&mut command, Stdio::piped())?;
+    let pid = child.id();
+    info!("Mode {} started (pid {})", mode, pid);
 
-    {
-        let killer_tx = killer_tx.clone();
-        simple_signal::set_handler(&[Signal::Term, Signal::Int], move |_| {
-            killer_tx.send(KillerMessage::Signal).unwrap()
-        });
+    // A mode which does not read its stdin must not block the server
+    let stdin = child.stdin.take();
+    if let Some(stdin) = &stdin {
+        fcntl(stdin.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK)).map_err(io::Error::other)?;
     }
 
-    thread::spawn(move || {
-        for msg in killer_rx {
-            match msg {
-                KillerMessage::Signal => {
-                    for child in children.drain(..) {
-                        if let Err(e) = kill(Pid::from_raw(child.id() as i32), SIGTERM) {
-                            eprintln!("{}", e);
-                        }
-                    }
-                    for (_, child) in status_children.drain() {
-                        if let Err(e) = kill(Pid::from_raw(child.id() as i32), SIGTERM) {
-                            eprintln!("{}", e);
-                        }
-                    }
-                    std::process::exit(0);
-                }
-                KillerMessage::Child(id, child) => {
-                    status_children.insert(id, child);
-                }
-                KillerMessage::Kill(id) => {
-                    status_children.remove(&id).map(kill_child);
-                }
+    Ok(RunningMode {
+        mode,
+        id,
+        pid,
+        stdin,
+        child: Some(child),
+        stdout: Some(stdout),
+    })
+}
+
+fn terminate(pid: u32) {
+    if let Err(e) = kill(Pid::from_raw(pid as i32), Signal::SIGTERM) {
+        error!("Terminating process {}: {}", pid, e);
+    }
+}
+
+impl Exiting {
+    fn terminate(&mut self, child: Child) {
+        debug!("Terminating process {}", child.id());
+        terminate(child.id());
+        self.0.push((child, Instant::now() + KILL_TIMEOUT));
+    }
+
+    /// Reaps children which exited and kills the ones which did not exit in time.
+    fn reap(&mut self, now: Instant) {
+        self.0.retain_mut(|(child, deadline)| {
+            let pid = child.id();
+            match child.try_wait() {
+                Ok(Some(status)) => info!("Process {} exited: {}", pid, status),
+                Ok(None) if now < *deadline => return true,
+                Ok(None) => match child.kill().and_then(|_| child.wait()) {
+                    Ok(status) => info!("Process {} killed: {}", pid, status),
+                    Err(e) => error!("Killing process {}: {}", pid, e),
+                },
+                Err(e) => error!("Waiting for process {}: {}", pid, e),
             }
-        }
-    });
+            false
+        });
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.0.iter().map(|&(_, deadline)| deadline).min()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Opens and locks the pidfile, so that a second server does not steal the message queue
+/// of the running one.  The lock is held until the server exits.
+fn lock(path: &Path) -> Result<File, Box<dyn Error>> {
+    let mut file = config::no_follow()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
 
-    Ok(killer_tx)
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => Ok(file),
+        Err(nix::Error::Sys(Errno::EAGAIN)) => {
+            let mut pid = String::new();
+            file.read_to_string(&mut pid)?;
+            Err(format!("Server is already running (pid {})", pid.trim()).into())
+        }
+        Err(e) => Err(e.into()),
+    }
 }
 
-fn start_daemon() -> Result<(), Box<dyn Error>> {
-    let mut path = dirs::runtime_dir().unwrap();
-    path.push(CONFIG_DIR);
-    let daemon = Daemonize::new().stderr(File::create(path)?);
+/// Detaches from the terminal.  If `keep_stdout` is set, the daemon keeps writing to the
+/// stdout of the server, which is the bar of the stdout backend.
+fn start_daemon(runtime_dir: &Path, keep_stdout: bool) -> Result<(), Box<dyn Error>> {
+    let path = runtime_dir.join(CONFIG_DIR);
+    let stderr = config::no_follow()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    let mut daemon = Daemonize::new().stderr(stderr);
+    if keep_stdout {
+        daemon = daemon.stdout(File::from(io::stdout().as_fd().try_clone_to_owned()?));
+    }
     daemon.start()?;
     Ok(())
 }
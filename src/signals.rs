@@ -0,0 +1,44 @@
+use {
+    nix::sys::{
+        signal::{SigSet, Signal},
+        signalfd::{SfdFlags, SignalFd},
+    },
+    std::{io, os::unix::process::CommandExt, process::Command},
+};
+
+/// Signals handled by the server.
+const SIGNALS: &[Signal] = &[
+    Signal::SIGTERM,
+    Signal::SIGINT,
+    Signal::SIGHUP,
+    Signal::SIGCHLD,
+];
+
+fn mask() -> SigSet {
+    let mut mask = SigSet::empty();
+    for &signal in SIGNALS {
+        mask.add(signal);
+    }
+    mask
+}
+
+/// Blocks the signals handled by the server and returns a descriptor to read them from.
+/// Children have to be spawned with `unblocked`, as they inherit the signal mask.
+pub fn block() -> io::Result<SignalFd> {
+    let mask = mask();
+    mask.thread_block().map_err(io::Error::other)?;
+    SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)
+        .map_err(io::Error::other)
+}
+
+/// Unblocks the signals blocked by `block` in the process spawned by the command.
+pub fn unblocked(command: &mut Command) -> &mut Command {
+    // SAFETY: only `pthread_sigmask` is called after fork, which is async-signal-safe
+    unsafe {
+        command.pre_exec(|| {
+            mask()
+                .thread_unblock()
+                .map_err(|_| io::Error::last_os_error())
+        })
+    }
+}